@@ -0,0 +1,226 @@
+use std::io::{self, Read};
+use std::ops::Range;
+
+// Content-defined chunking based on FastCDC. A gear hash rolls over the data and a chunk
+// ends wherever the hash matches a mask, so boundaries move with the content instead of
+// sitting at fixed offsets. Inserting a byte only changes the chunks around the edit.
+
+// Random 64-bit value per byte, generated with splitmix64 so every build gets the same table
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6473_746f_7261_6765; // "dstorage"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+// Mask over the top bits of the hash. The low bits of a gear hash only depend on the
+// last few bytes, the top ones on the whole 64 byte window.
+fn mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkSizes {
+    pub min_size: usize,
+    pub average_size: usize,
+    pub max_size: usize,
+}
+
+impl ChunkSizes {
+    // 1 MiB / 4 MiB / 16 MiB, in line with the default fixed chunk size
+    pub const DEFAULT: ChunkSizes = ChunkSizes {
+        min_size: 1024 * 1024,
+        average_size: 4 * 1024 * 1024,
+        max_size: 16 * 1024 * 1024,
+    };
+}
+
+// Length of the chunk starting at the beginning of data
+fn cut_point(data: &[u8], sizes: &ChunkSizes) -> usize {
+    let end = data.len().min(sizes.max_size.max(1));
+    if end <= sizes.min_size {
+        return end;
+    }
+    let normal_end = end.min(sizes.average_size);
+
+    // Normalized chunking: a harder mask before the average size and an easier one after
+    // it keeps most chunks close to the average
+    let bits = sizes.average_size.max(2).ilog2();
+    let hard_mask = mask((bits + 1).min(63));
+    let easy_mask = mask((bits - 1).max(1));
+
+    let mut hash: u64 = 0;
+    let mut position = sizes.min_size;
+    while position < normal_end {
+        hash = (hash << 1).wrapping_add(GEAR[data[position] as usize]);
+        if hash & hard_mask == 0 {
+            return position + 1;
+        }
+        position += 1;
+    }
+    while position < end {
+        hash = (hash << 1).wrapping_add(GEAR[data[position] as usize]);
+        if hash & easy_mask == 0 {
+            return position + 1;
+        }
+        position += 1;
+    }
+    end
+}
+
+// Split data into content-defined ranges covering all of it
+pub fn chunk_ranges(data: &[u8], sizes: &ChunkSizes) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let length = cut_point(&data[start..], sizes);
+        ranges.push(start..start + length);
+        start += length;
+    }
+    ranges
+}
+
+// Content-defined chunks read from a stream. At most max_size bytes are buffered, which is
+// all cut_point looks at, so the chunks are the same chunk_ranges gives for the whole data.
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    sizes: ChunkSizes,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R, sizes: ChunkSizes) -> ChunkReader<R> {
+        ChunkReader {
+            reader,
+            sizes,
+            buffer: Vec::new(),
+            eof: false,
+        }
+    }
+
+    // Read until max_size bytes are buffered or the stream ends
+    fn fill(&mut self) -> io::Result<()> {
+        let wanted = self.sizes.max_size.max(1).saturating_sub(self.buffer.len());
+        if self.eof || wanted == 0 {
+            return Ok(());
+        }
+        let read = (&mut self.reader).take(wanted as u64).read_to_end(&mut self.buffer)?;
+        if read < wanted {
+            self.eof = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let length = cut_point(&self.buffer, &self.sizes);
+        Some(Ok(self.buffer.drain(..length).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMALL: ChunkSizes = ChunkSizes { min_size: 64, average_size: 256, max_size: 1024 };
+
+    // Incompressible bytes from xorshift, so boundaries depend on the content and not on a
+    // repeating pattern
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks<'a>(data: &'a [u8], sizes: &ChunkSizes) -> Vec<&'a [u8]> {
+        chunk_ranges(data, sizes).into_iter().map(|range| &data[range]).collect()
+    }
+
+    // Hands out at most a few bytes per read, like a socket or a pipe would
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.step.min(buf.len()).min(self.data.len());
+            buf[..read].copy_from_slice(&self.data[..read]);
+            self.data = &self.data[read..];
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn chunks_stay_within_the_sizes() {
+        let data = random_bytes(256 * 1024, 1);
+        let ranges = chunk_ranges(&data, &SMALL);
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, data.len());
+        assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+        assert!(ranges.iter().all(|range| range.len() <= SMALL.max_size));
+        assert!(ranges[..ranges.len() - 1].iter().all(|range| range.len() >= SMALL.min_size));
+
+        // Normalized chunking keeps the average close to the one asked for
+        let average = data.len() / ranges.len();
+        assert!(average > SMALL.average_size / 2 && average < SMALL.average_size * 2, "average chunk of {} bytes", average);
+
+        // Data that never matches the mask is cut at max_size
+        let zeros = vec![0u8; 3 * SMALL.max_size + 5];
+        let lengths: Vec<usize> = chunk_ranges(&zeros, &SMALL).iter().map(|range| range.len()).collect();
+        assert_eq!(lengths, vec![SMALL.max_size, SMALL.max_size, SMALL.max_size, 5]);
+
+        assert!(chunk_ranges(&[], &SMALL).is_empty());
+        assert_eq!(chunk_ranges(&data[..10], &SMALL), vec![0..10]);
+    }
+
+    #[test]
+    fn an_insert_only_changes_the_chunks_around_it() {
+        let data = random_bytes(256 * 1024, 2);
+        let mut edited = data.clone();
+        edited.insert(100, 0x42);
+
+        let before = chunks(&data, &SMALL);
+        let after = chunks(&edited, &SMALL);
+        let changed = after.iter().filter(|chunk| !before.contains(chunk)).count();
+        assert!(changed <= 2, "{} of {} chunks changed", changed, after.len());
+        assert!(after.len() > 100);
+    }
+
+    #[test]
+    fn chunk_reader_cuts_where_chunk_ranges_does() {
+        for (len, step) in [(0, 1), (10, 3), (SMALL.max_size, 7), (64 * 1024 + 17, 5), (64 * 1024, 4096), (200 * 1024, 100_000)] {
+            let data = random_bytes(len, len as u64 + 3);
+            let reader = ChunkReader::new(Trickle { data: &data, step }, SMALL);
+            let read: Vec<Vec<u8>> = reader.collect::<io::Result<_>>().unwrap();
+            let expected: Vec<Vec<u8>> = chunks(&data, &SMALL).into_iter().map(|chunk| chunk.to_vec()).collect();
+            assert_eq!(read, expected, "{} bytes read {} at a time", len, step);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use crate::manifest::Manifest;
+
+// Content-addressed storage for encoded chunks. Every chunk lives at a path derived from
+// the SHA-256 of its bytes, so a chunk shared by several files (or uploaded twice) is
+// only stored once. pointers.db keeps a reference count per chunk and, per file name, the
+// manifest listing the chunks that make up the file. Chunks held as replicas for other
+// nodes are referenced from replica_refs instead, once per owner and file name, so a
+// repeated store or release of the same replica doesn't skew the count.
+pub struct ChunkStore {
+    root: PathBuf,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// SHA-256 of everything left in reader, read a block at a time
+pub fn sha256_hex_reader<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut block = vec![0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut block)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&block[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn is_chunk_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+impl ChunkStore {
+    pub fn new(root: impl Into<PathBuf>) -> ChunkStore {
+        ChunkStore { root: root.into() }
+    }
+
+    pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS chunks (
+                hash TEXT PRIMARY KEY,
+                size INTEGER NOT NULL,
+                refCount INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS replica_refs (
+                owner TEXT NOT NULL,
+                fileName TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                PRIMARY KEY (owner, fileName, hash)
+            )",
+            [],
+        )?;
+        Manifest::create_tables(conn)
+    }
+
+    // Chunks are spread over 256 directories named after the first byte of their hash
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[..2]).join(hash)
+    }
+
+    // Store a chunk and return its hash. The chunk is unreferenced until a file that
+    // contains it is saved with save_file.
+    pub fn put(&self, conn: &Connection, data: &[u8]) -> Result<String, Box<dyn Error>> {
+        let hash = sha256_hex(data);
+        let path = self.chunk_path(&hash);
+
+        if !path.exists() {
+            let directory = path.parent().unwrap();
+            fs::create_dir_all(directory)?;
+
+            // Write next to the final path and rename, so a chunk file is never half written
+            let temporary_path = directory.join(format!("{}.tmp", hash));
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&temporary_path)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+            fs::rename(&temporary_path, &path)?;
+            println!("Chunk written to: {}", path.display());
+        }
+
+        conn.execute(
+            "INSERT OR IGNORE INTO chunks (hash, size, refCount) VALUES (?1, ?2, 0)",
+            params![hash, data.len() as i64],
+        )?;
+        Ok(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> io::Result<Vec<u8>> {
+        if !is_chunk_hash(hash) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a chunk hash", hash)));
+        }
+        let data = fs::read(self.chunk_path(hash))?;
+
+        // The file name is the hash of its content, anything else means the disk copy rotted
+        let actual = sha256_hex(&data);
+        if actual != hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chunk {} is corrupt on disk, its content hashes to {}", hash, actual),
+            ));
+        }
+        Ok(data)
+    }
+
+    // Store a replica sent by owner for one of its files
+    pub fn hold_replica(&self, conn: &Connection, owner: &str, file_name: &str, data: &[u8]) -> Result<String, Box<dyn Error>> {
+        let hash = self.put(conn, data)?;
+        conn.execute(
+            "INSERT OR IGNORE INTO replica_refs (owner, fileName, hash) VALUES (?1, ?2, ?3)",
+            params![owner, file_name, hash],
+        )?;
+        Ok(hash)
+    }
+
+    // Owner no longer needs the replica for file_name, remove it when nothing else does
+    pub fn release_replica(&self, conn: &Connection, owner: &str, file_name: &str, hash: &str) -> Result<(), Box<dyn Error>> {
+        conn.execute(
+            "DELETE FROM replica_refs WHERE owner = ?1 AND fileName = ?2 AND hash = ?3",
+            params![owner, file_name, hash],
+        )?;
+        self.remove_if_unreferenced(conn, hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        is_chunk_hash(hash) && self.chunk_path(hash).exists()
+    }
+
+    // Save the manifest, taking a reference on each of its chunks. Chunks of a file that
+    // previously had the same name lose a reference instead of being overwritten. Returns
+    // the manifest that was replaced, its replicas on other nodes are the caller's to release.
+    pub fn save_file(&self, conn: &mut Connection, manifest: &Manifest) -> Result<Option<Manifest>, Box<dyn Error>> {
+        let transaction = conn.transaction()?;
+        let previous = ChunkStore::release_file(&transaction, &manifest.file_name)?;
+
+        for hash in manifest.stored_hashes() {
+            let updated = transaction.execute("UPDATE chunks SET refCount = refCount + 1 WHERE hash = ?1", params![hash])?;
+            if updated == 0 {
+                return Err(format!("Chunk {} was never stored", hash).into());
+            }
+        }
+        manifest.save(&transaction)?;
+        transaction.commit()?;
+
+        self.remove_unreferenced(conn, previous.as_ref())?;
+        Ok(previous)
+    }
+
+    // Drop a file name, releasing its chunks. Returns the manifest that was dropped, its
+    // replicas on other nodes are the caller's to release.
+    pub fn delete_file(&self, conn: &mut Connection, file_name: &str) -> Result<Option<Manifest>, Box<dyn Error>> {
+        let transaction = conn.transaction()?;
+        let previous = ChunkStore::release_file(&transaction, file_name)?;
+        Manifest::delete(&transaction, file_name)?;
+        transaction.commit()?;
+
+        self.remove_unreferenced(conn, previous.as_ref())?;
+        Ok(previous)
+    }
+
+    // Take away the references held by the current manifest for file_name, returns that
+    // manifest
+    fn release_file(conn: &Connection, file_name: &str) -> rusqlite::Result<Option<Manifest>> {
+        let previous = Manifest::load(conn, file_name)?;
+        if let Some(previous) = &previous {
+            for hash in previous.stored_hashes() {
+                conn.execute("UPDATE chunks SET refCount = refCount - 1 WHERE hash = ?1", params![hash])?;
+            }
+        }
+        Ok(previous)
+    }
+
+    fn remove_unreferenced(&self, conn: &Connection, previous: Option<&Manifest>) -> Result<(), Box<dyn Error>> {
+        for hash in previous.map(Manifest::stored_hashes).unwrap_or_default() {
+            self.remove_if_unreferenced(conn, hash)?;
+        }
+        Ok(())
+    }
+
+    fn remove_if_unreferenced(&self, conn: &Connection, hash: &str) -> Result<(), Box<dyn Error>> {
+        let ref_count: Option<i64> = conn
+            .query_row("SELECT refCount FROM chunks WHERE hash = ?1", params![hash], |row| row.get(0))
+            .optional()?;
+
+        let replica_refs: i64 = conn.query_row("SELECT COUNT(*) FROM replica_refs WHERE hash = ?1", params![hash], |row| row.get(0))?;
+
+        if ref_count.is_some_and(|count| count <= 0) && replica_refs == 0 {
+            conn.execute("DELETE FROM chunks WHERE hash = ?1", params![hash])?;
+            let path = self.chunk_path(hash);
+            if path.exists() {
+                fs::remove_file(&path)?;
+                println!("Chunk removed: {}", path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecId;
+    use crate::encryption::Encryption;
+    use crate::manifest::ChunkEntry;
+
+    // A chunk store in its own temporary directory with an in-memory database, the
+    // directory is removed when the test is done
+    struct TempStore {
+        store: ChunkStore,
+        conn: Connection,
+    }
+
+    impl TempStore {
+        fn new(name: &str) -> TempStore {
+            let root = std::env::temp_dir().join(format!("dstorage-chunks-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&root);
+            let conn = Connection::open_in_memory().unwrap();
+            ChunkStore::create_tables(&conn).unwrap();
+            TempStore { store: ChunkStore::new(root), conn }
+        }
+
+        fn put(&self, data: &[u8]) -> String {
+            self.store.put(&self.conn, data).unwrap()
+        }
+
+        fn ref_count(&self, hash: &str) -> Option<i64> {
+            self.conn
+                .query_row("SELECT refCount FROM chunks WHERE hash = ?1", params![hash], |row| row.get(0))
+                .optional()
+                .unwrap()
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.store.root);
+        }
+    }
+
+    // A file made of the given chunks, stored as they are
+    fn manifest(file_name: &str, chunks: &[&[u8]]) -> Manifest {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        for data in chunks {
+            entries.push(ChunkEntry {
+                offset,
+                size: data.len() as u64,
+                stored_size: data.len() as u64,
+                hash: sha256_hex(data),
+                codec: CodecId::Stored,
+                nodes: vec!["10.0.0.1".into()],
+            });
+            offset += data.len() as u64;
+        }
+        Manifest {
+            file_name: file_name.into(),
+            size: offset,
+            hash: sha256_hex(&chunks.concat()),
+            replication_factor: 1,
+            encryption: Encryption::None,
+            chunks: entries,
+            erasure: None,
+            parity: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn stores_each_chunk_once_and_checks_it_on_read() {
+        let mut store = TempStore::new("dedup");
+        let hash = store.put(b"shared chunk");
+        assert_eq!(store.put(b"shared chunk"), hash);
+        assert_eq!(fs::read_dir(store.store.chunk_path(&hash).parent().unwrap()).unwrap().count(), 1);
+        assert_eq!(store.ref_count(&hash), Some(0));
+        assert_eq!(store.store.get(&hash).unwrap(), b"shared chunk");
+        assert!(store.store.contains(&hash));
+
+        assert_eq!(store.store.get("../../etc/passwd").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.store.get(&sha256_hex(b"never stored")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::write(store.store.chunk_path(&hash), b"rotted chunk").unwrap();
+        assert_eq!(store.store.get(&hash).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // A manifest can only take references on chunks that were stored
+        let unstored = manifest("ghost.txt", &[b"never stored"]);
+        assert!(store.store.save_file(&mut store.conn, &unstored).is_err());
+        assert_eq!(Manifest::load(&store.conn, "ghost.txt").unwrap(), None);
+    }
+
+    #[test]
+    fn counts_references_from_files() {
+        let mut store = TempStore::new("files");
+        let (a, shared, b) = (store.put(b"only in a"), store.put(b"in both"), store.put(b"only in b"));
+        let first = manifest("a.txt", &[b"only in a", b"in both"]);
+        assert_eq!(store.store.save_file(&mut store.conn, &first).unwrap(), None);
+        store.store.save_file(&mut store.conn, &manifest("b.txt", &[b"in both", b"only in b"])).unwrap();
+        assert_eq!((store.ref_count(&a), store.ref_count(&shared), store.ref_count(&b)), (Some(1), Some(2), Some(1)));
+
+        // Replacing a file releases the chunks only its previous version used
+        let replaced = store.put(b"new in a");
+        let second = manifest("a.txt", &[b"new in a", b"in both"]);
+        assert_eq!(store.store.save_file(&mut store.conn, &second).unwrap(), Some(first));
+        assert_eq!((store.ref_count(&a), store.ref_count(&replaced), store.ref_count(&shared)), (None, Some(1), Some(2)));
+        assert!(!store.store.contains(&a));
+
+        assert!(store.store.delete_file(&mut store.conn, "b.txt").unwrap().is_some());
+        assert_eq!((store.ref_count(&shared), store.ref_count(&b)), (Some(1), None));
+        assert!(store.store.contains(&shared) && !store.store.contains(&b));
+
+        assert_eq!(store.store.delete_file(&mut store.conn, "a.txt").unwrap(), Some(second));
+        assert!(!store.store.contains(&shared) && !store.store.contains(&replaced));
+        assert_eq!(store.store.delete_file(&mut store.conn, "a.txt").unwrap(), None);
+    }
+
+    #[test]
+    fn counts_references_from_replica_owners() {
+        let mut store = TempStore::new("replicas");
+        let hash = store.store.hold_replica(&store.conn, "10.0.0.7", "a.txt", b"replica").unwrap();
+        store.store.hold_replica(&store.conn, "10.0.0.7", "a.txt", b"replica").unwrap();
+        store.store.hold_replica(&store.conn, "10.0.0.8", "b.txt", b"replica").unwrap();
+
+        // A repeated hold counts once, the chunk stays until every owner released it
+        store.store.release_replica(&store.conn, "10.0.0.7", "a.txt", &hash).unwrap();
+        assert!(store.store.contains(&hash));
+        store.store.release_replica(&store.conn, "10.0.0.7", "a.txt", &hash).unwrap();
+        store.store.release_replica(&store.conn, "10.0.0.8", "b.txt", &hash).unwrap();
+        assert!(!store.store.contains(&hash));
+        assert_eq!(store.ref_count(&hash), None);
+
+        // A chunk both held as a replica and used by a local file needs both released
+        let hash = store.store.hold_replica(&store.conn, "10.0.0.7", "a.txt", b"both").unwrap();
+        store.store.save_file(&mut store.conn, &manifest("local.txt", &[b"both"])).unwrap();
+        store.store.release_replica(&store.conn, "10.0.0.7", "a.txt", &hash).unwrap();
+        assert!(store.store.contains(&hash));
+        store.store.hold_replica(&store.conn, "10.0.0.7", "a.txt", b"both").unwrap();
+        store.store.delete_file(&mut store.conn, "local.txt").unwrap();
+        assert!(store.store.contains(&hash));
+        store.store.release_replica(&store.conn, "10.0.0.7", "a.txt", &hash).unwrap();
+        assert!(!store.store.contains(&hash));
+
+        // Only chunks nothing references are removed
+        let kept = store.store.hold_replica(&store.conn, "10.0.0.7", "a.txt", b"kept").unwrap();
+        let unreferenced = store.put(b"unreferenced");
+        store.store.remove_if_unreferenced(&store.conn, &kept).unwrap();
+        store.store.remove_if_unreferenced(&store.conn, &unreferenced).unwrap();
+        assert!(store.store.contains(&kept) && !store.store.contains(&unreferenced));
+    }
+}
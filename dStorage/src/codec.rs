@@ -0,0 +1,376 @@
+use std::fmt;
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+use crate::dictionary::Dictionary;
+use crate::{Compressor, Decoder};
+
+// Identifier of a codec, stored next to every encoded file so it can be decoded later
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CodecId {
+    Stored = 0,
+    Huffman = 1,
+    Lz77 = 2,
+}
+
+impl CodecId {
+    pub const ALL: [CodecId; 3] = [CodecId::Stored, CodecId::Huffman, CodecId::Lz77];
+
+    pub fn from_u8(id: u8) -> Option<CodecId> {
+        CodecId::ALL.into_iter().find(|codec_id| *codec_id as u8 == id)
+    }
+
+    pub fn codec(self) -> Box<dyn Codec> {
+        match self {
+            CodecId::Stored => Box::new(StoredCodec),
+            CodecId::Huffman => Box::new(HuffmanCodec),
+            CodecId::Lz77 => Box::new(Lz77Codec),
+        }
+    }
+}
+
+impl fmt::Display for CodecId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CodecId::Stored => "stored",
+            CodecId::Huffman => "huffman",
+            CodecId::Lz77 => "lz77",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ToSql for CodecId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as u8 as i64))
+    }
+}
+
+impl FromSql for CodecId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let id = value.as_i64()?;
+        u8::try_from(id)
+            .ok()
+            .and_then(CodecId::from_u8)
+            .ok_or(FromSqlError::OutOfRange(id))
+    }
+}
+
+pub trait Codec {
+    fn id(&self) -> CodecId;
+    fn encode(&self, data: &[u8]) -> Vec<u8>;
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+// Encode with every codec and keep the smallest output. Ties go to the codec listed first
+// in CodecId::ALL, so data that doesn't compress is stored as it is.
+pub fn encode_smallest(data: &[u8]) -> (CodecId, Vec<u8>) {
+    let mut best: Option<(CodecId, Vec<u8>)> = None;
+    for codec_id in CodecId::ALL {
+        let encoded = codec_id.codec().encode(data);
+        if best.as_ref().is_none_or(|(_, smallest)| encoded.len() < smallest.len()) {
+            best = Some((codec_id, encoded));
+        }
+    }
+    best.expect("There is always at least one codec")
+}
+
+// Keeps the data as it is, for media and archives that are already compressed
+pub struct StoredCodec;
+
+impl Codec for StoredCodec {
+    fn id(&self) -> CodecId {
+        CodecId::Stored
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(encoded.to_vec())
+    }
+}
+
+// Huffman coding through Compressor and Decoder. The serialized dictionary is stored in
+// front of the packed data: dictionary length (u32, big-endian), dictionary, packed data.
+pub struct HuffmanCodec;
+
+impl Codec for HuffmanCodec {
+    fn id(&self) -> CodecId {
+        CodecId::Huffman
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let compressor = Compressor { data: data.to_vec() };
+        let (encoded_data, encoding_table) = compressor.compress();
+        let dictionary = Dictionary::from_encoding_table(&encoding_table)
+            .expect("Compressor never produces codes over the dictionary limit")
+            .to_bytes();
+
+        let mut encoded = Vec::with_capacity(4 + dictionary.len() + encoded_data.len());
+        encoded.extend_from_slice(&(dictionary.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(&dictionary);
+        encoded.extend_from_slice(&encoded_data);
+        encoded
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, String> {
+        if encoded.len() < 4 {
+            return Err("Huffman data is missing its dictionary length".into());
+        }
+        let dictionary_len = u32::from_be_bytes(encoded[..4].try_into().unwrap()) as usize;
+        let rest = &encoded[4..];
+        if rest.len() < dictionary_len {
+            return Err("Huffman data is shorter than its dictionary".into());
+        }
+
+        let (dictionary, encoded_data) = rest.split_at(dictionary_len);
+        Decoder::from_dictionary(encoded_data.to_vec(), dictionary)?.decode()
+    }
+}
+
+// LZ77 with a 64 KiB window. After the original length (u64, big-endian) the data is a
+// sequence of tokens, each starting with a control byte:
+//
+//   0x00..=0x7F   literal run, followed by (control + 1) literal bytes
+//   0x80..=0xFF   match of (control - 0x80 + MIN_MATCH) bytes, followed by its distance (u16)
+pub struct Lz77Codec;
+
+const WINDOW_SIZE: usize = u16::MAX as usize;
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + 0x7F;
+const MAX_LITERAL_RUN: usize = 0x80;
+const HASH_BITS: u32 = 15;
+const MAX_CHAIN_LENGTH: usize = 32;
+const NO_POSITION: usize = usize::MAX;
+
+impl Lz77Codec {
+    fn hash(bytes: &[u8]) -> usize {
+        let value = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+        (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(data: &[u8], position: usize, head: &mut [usize], previous: &mut [usize]) {
+        if position + MIN_MATCH <= data.len() {
+            let hash = Lz77Codec::hash(&data[position..]);
+            previous[position] = head[hash];
+            head[hash] = position;
+        }
+    }
+
+    fn flush_literals(literals: &[u8], encoded: &mut Vec<u8>) {
+        for run in literals.chunks(MAX_LITERAL_RUN) {
+            encoded.push((run.len() - 1) as u8);
+            encoded.extend_from_slice(run);
+        }
+    }
+}
+
+impl Codec for Lz77Codec {
+    fn id(&self) -> CodecId {
+        CodecId::Lz77
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(8 + data.len() + data.len() / MAX_LITERAL_RUN + 1);
+        encoded.extend_from_slice(&(data.len() as u64).to_be_bytes());
+
+        // head holds the latest position for every hash, previous chains older positions
+        let mut head = vec![NO_POSITION; 1 << HASH_BITS];
+        let mut previous = vec![NO_POSITION; data.len()];
+        let mut literal_start = 0;
+        let mut position = 0;
+
+        while position < data.len() {
+            let mut best_length = 0;
+            let mut best_distance = 0;
+
+            if position + MIN_MATCH <= data.len() {
+                let max_length = MAX_MATCH.min(data.len() - position);
+                let mut candidate = head[Lz77Codec::hash(&data[position..])];
+                let mut chain = 0;
+                while candidate != NO_POSITION && position - candidate <= WINDOW_SIZE && chain < MAX_CHAIN_LENGTH {
+                    let length = data[candidate..]
+                        .iter()
+                        .zip(&data[position..position + max_length])
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    if length > best_length {
+                        best_length = length;
+                        best_distance = position - candidate;
+                        if length == max_length {
+                            break;
+                        }
+                    }
+                    candidate = previous[candidate];
+                    chain += 1;
+                }
+            }
+
+            if best_length >= MIN_MATCH {
+                Lz77Codec::flush_literals(&data[literal_start..position], &mut encoded);
+                encoded.push(0x80 | (best_length - MIN_MATCH) as u8);
+                encoded.extend_from_slice(&(best_distance as u16).to_be_bytes());
+                for skipped in position..position + best_length {
+                    Lz77Codec::insert(data, skipped, &mut head, &mut previous);
+                }
+                position += best_length;
+                literal_start = position;
+            } else {
+                Lz77Codec::insert(data, position, &mut head, &mut previous);
+                position += 1;
+            }
+        }
+        Lz77Codec::flush_literals(&data[literal_start..], &mut encoded);
+        encoded
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, String> {
+        if encoded.len() < 8 {
+            return Err("LZ77 data is missing its length".into());
+        }
+        let original_len = u64::from_be_bytes(encoded[..8].try_into().unwrap());
+        // A literal token never expands more than 129 bytes out of 128, a match at most
+        // MAX_MATCH bytes out of 3, which bounds any honest length claim
+        if original_len > (encoded.len() as u64 - 8) * MAX_MATCH as u64 {
+            return Err(format!("LZ77 data claims {} bytes, more than it can hold", original_len));
+        }
+
+        let mut decoded = Vec::with_capacity(original_len as usize);
+        let mut position = 8;
+        while position < encoded.len() {
+            let control = encoded[position] as usize;
+            position += 1;
+
+            if control < 0x80 {
+                let run_length = control + 1;
+                let run = encoded
+                    .get(position..position + run_length)
+                    .ok_or("LZ77 literal run is cut short")?;
+                decoded.extend_from_slice(run);
+                position += run_length;
+            } else {
+                let length = control - 0x80 + MIN_MATCH;
+                let distance_bytes = encoded
+                    .get(position..position + 2)
+                    .ok_or("LZ77 match is missing its distance")?;
+                let distance = u16::from_be_bytes(distance_bytes.try_into().unwrap()) as usize;
+                position += 2;
+
+                if distance == 0 || distance > decoded.len() {
+                    return Err(format!("LZ77 match distance {} points outside the decoded data", distance));
+                }
+                // Copy byte by byte, a match may overlap the bytes it produces
+                let start = decoded.len() - distance;
+                for i in 0..length {
+                    decoded.push(decoded[start + i]);
+                }
+            }
+        }
+
+        if decoded.len() as u64 != original_len {
+            return Err(format!("LZ77 data decoded to {} bytes instead of {}", decoded.len(), original_len));
+        }
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> Vec<Vec<u8>> {
+        vec![
+            Vec::new(),
+            vec![42],
+            vec![7; 10_000],
+            b"abracadabra, abracadabra, abracadabra".repeat(50),
+            (0..=255).collect(),
+            (0..50_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect(),
+        ]
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        for codec_id in CodecId::ALL {
+            let codec = codec_id.codec();
+            assert_eq!(codec.id(), codec_id);
+            for data in samples() {
+                let encoded = codec.encode(&data);
+                assert_eq!(codec.decode(&encoded).unwrap(), data, "{} on {} bytes", codec_id, data.len());
+            }
+        }
+    }
+
+    #[test]
+    fn ids_survive_a_byte_and_sqlite() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        for codec_id in CodecId::ALL {
+            assert_eq!(CodecId::from_u8(codec_id as u8), Some(codec_id));
+            let stored: CodecId = conn.query_row("SELECT ?1", [codec_id], |row| row.get(0)).unwrap();
+            assert_eq!(stored, codec_id);
+        }
+        assert_eq!(CodecId::from_u8(3), None);
+        assert!(conn.query_row("SELECT 300", [], |row| row.get::<_, CodecId>(0)).is_err());
+    }
+
+    #[test]
+    fn huffman_rejects_malformed_input() {
+        let codec = HuffmanCodec;
+        assert!(codec.decode(&[]).is_err());
+        assert!(codec.decode(&[0, 0, 1]).is_err());
+        // Dictionary length past the end of the data
+        assert!(codec.decode(&[0, 0, 0, 9, 1, 2]).is_err());
+        // Dictionary that doesn't parse
+        assert!(codec.decode(&[0, 0, 0, 3, 0xFF, 0xFF, 0xFF, 0x00]).is_err());
+
+        let mut encoded = codec.encode(b"some text to encode");
+        encoded.truncate(5);
+        assert!(codec.decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn lz77_rejects_malformed_input() {
+        let codec = Lz77Codec;
+        let with_length = |length: u64, tokens: &[u8]| [&length.to_be_bytes()[..], tokens].concat();
+
+        assert!(codec.decode(&[0; 7]).is_err());
+        // More bytes claimed than the tokens could ever produce
+        assert!(codec.decode(&with_length(1 << 40, &[0x00, b'a'])).is_err());
+        // Literal run of 3 with only 2 bytes
+        assert!(codec.decode(&with_length(3, &[0x02, b'a', b'b'])).is_err());
+        // Match without its distance
+        assert!(codec.decode(&with_length(5, &[0x00, b'a', 0x80, 0x00])).is_err());
+        // Match reaching back before the start
+        assert!(codec.decode(&with_length(5, &[0x00, b'a', 0x80, 0x00, 0x02])).is_err());
+        assert!(codec.decode(&with_length(5, &[0x00, b'a', 0x80, 0x00, 0x00])).is_err());
+        // Tokens that decode to a different length than claimed
+        assert!(codec.decode(&with_length(2, &[0x00, b'a'])).is_err());
+        assert_eq!(codec.decode(&with_length(5, &[0x00, b'a', 0x80, 0x00, 0x01])).unwrap(), b"aaaaa");
+    }
+
+    #[test]
+    fn encode_smallest_keeps_incompressible_data_stored() {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let random: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 56) as u8
+            })
+            .collect();
+        for data in [Vec::new(), random] {
+            let (codec_id, encoded) = encode_smallest(&data);
+            assert_eq!(codec_id, CodecId::Stored);
+            assert_eq!(encoded, data);
+        }
+
+        let text = b"to be or not to be, that is the question. ".repeat(100);
+        let (codec_id, encoded) = encode_smallest(&text);
+        assert_ne!(codec_id, CodecId::Stored);
+        assert!(encoded.len() < text.len());
+        assert_eq!(codec_id.codec().decode(&encoded).unwrap(), text);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use crate::PACKED_HEADER_LEN;
+
+// Number of bits resolved by a single table lookup. Codes up to this length decode in one
+// step, longer (rare) codes fall back to a per-length lookup.
+const LOOKUP_BITS: u32 = 11;
+
+#[derive(Clone, Copy)]
+struct TableEntry {
+    symbol: u8,
+    length: u8, // 0 marks a slot that is the prefix of a longer code
+}
+
+// Lookup-table Huffman decoder for the packed format written by pack_bits
+pub struct DecodeTable {
+    table: Vec<TableEntry>,
+    long_codes: HashMap<(u8, u64), u8>,
+    longest_code: u8,
+}
+
+impl DecodeTable {
+    pub fn new(encoding_table: &HashMap<u8, String>) -> Result<DecodeTable, String> {
+        let mut table = vec![TableEntry { symbol: 0, length: 0 }; 1 << LOOKUP_BITS];
+        let mut long_codes = HashMap::new();
+        let mut longest_code = 0;
+
+        for (&symbol, code) in encoding_table {
+            let length = code.len() as u32;
+            if length == 0 {
+                return Err(format!("Byte {:#04x} has an empty code", symbol));
+            }
+            if length > 64 {
+                return Err(format!("Code for byte {:#04x} is longer than 64 bits", symbol));
+            }
+            let value = u64::from_str_radix(code, 2)
+                .map_err(|_| format!("Code for byte {:#04x} is not binary", symbol))?;
+            longest_code = longest_code.max(length as u8);
+
+            if length <= LOOKUP_BITS {
+                // Every slot starting with this code resolves to the symbol
+                let first_slot = (value << (LOOKUP_BITS - length)) as usize;
+                let slot_count = 1usize << (LOOKUP_BITS - length);
+                for entry in &mut table[first_slot..first_slot + slot_count] {
+                    *entry = TableEntry { symbol, length: length as u8 };
+                }
+            } else {
+                long_codes.insert((length as u8, value), symbol);
+            }
+        }
+
+        Ok(DecodeTable { table, long_codes, longest_code })
+    }
+
+    pub fn decode(&self, packed: &[u8]) -> Result<Vec<u8>, String> {
+        if packed.len() < PACKED_HEADER_LEN {
+            return Err("Packed data is missing its header".into());
+        }
+        let (header, data) = packed.split_at(PACKED_HEADER_LEN);
+        let padding = header[0];
+        if padding > 7 || (data.is_empty() && padding != 0) {
+            return Err(format!("Invalid padding length {}", padding));
+        }
+        let symbol_count = u64::from_be_bytes(header[1..].try_into().unwrap());
+
+        // Every code is at least one bit long, so a valid count can't exceed the bit count
+        let mut remaining_bits = data.len() as u64 * 8 - padding as u64;
+        if symbol_count > remaining_bits {
+            return Err(format!("Header declares {} symbols but only {} bits follow", symbol_count, remaining_bits));
+        }
+        let mut decoded_data = Vec::with_capacity(symbol_count as usize);
+
+        // The next bits of the stream sit at the top of bit_buffer. A refill tops it up to
+        // at least 121 bits, so even a 64 bit code is always whole in it.
+        let mut bit_buffer: u128 = 0;
+        let mut bits_in_buffer: u32 = 0;
+        let mut next_byte = 0;
+
+        while (decoded_data.len() as u64) < symbol_count {
+            while bits_in_buffer <= 120 && next_byte < data.len() {
+                bit_buffer |= (data[next_byte] as u128) << (120 - bits_in_buffer);
+                bits_in_buffer += 8;
+                next_byte += 1;
+            }
+            let available_bits = (bits_in_buffer as u64).min(remaining_bits) as u32;
+
+            let entry = self.table[(bit_buffer >> (128 - LOOKUP_BITS)) as usize];
+            let (symbol, length) = if entry.length != 0 {
+                (entry.symbol, entry.length as u32)
+            } else {
+                self.decode_long_code(bit_buffer, available_bits)?
+            };
+
+            if length > available_bits {
+                return Err("Encoded text ends in the middle of a code".into());
+            }
+
+            decoded_data.push(symbol);
+            bit_buffer = bit_buffer.checked_shl(length).unwrap_or(0);
+            bits_in_buffer -= length;
+            remaining_bits -= length as u64;
+        }
+
+        if remaining_bits != 0 {
+            return Err(format!("{} bits left over after the last symbol", remaining_bits));
+        }
+        Ok(decoded_data)
+    }
+
+    fn decode_long_code(&self, bit_buffer: u128, available_bits: u32) -> Result<(u8, u32), String> {
+        let longest = (self.longest_code as u32).min(available_bits);
+        for length in (LOOKUP_BITS + 1)..=longest {
+            let value = (bit_buffer >> (128 - length)) as u64;
+            if let Some(&symbol) = self.long_codes.get(&(length as u8, value)) {
+                return Ok((symbol, length));
+            }
+        }
+
+        if available_bits < self.longest_code as u32 {
+            Err("Encoded text ends in the middle of a code".into())
+        } else {
+            Err("Encoded text contains a bit pattern that is not in the encoding table".into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::dictionary::{canonical_codes, Dictionary};
+    use crate::pack_bits;
+
+    fn table(pairs: &[(u8, u8)]) -> HashMap<u8, String> {
+        canonical_codes(&pairs.iter().copied().collect::<BTreeMap<u8, u8>>())
+    }
+
+    fn encode(encoding_table: &HashMap<u8, String>, data: &[u8]) -> Vec<u8> {
+        let bits: Vec<u8> = data
+            .iter()
+            .flat_map(|symbol| encoding_table[symbol].bytes().map(|bit| bit - b'0'))
+            .collect();
+        pack_bits(&bits, data.len() as u64)
+    }
+
+    fn round_trip(encoding_table: &HashMap<u8, String>, data: &[u8]) {
+        let decoded = DecodeTable::new(encoding_table).unwrap().decode(&encode(encoding_table, data)).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn decodes_short_codes() {
+        let encoding_table = table(&[(b'a', 1), (b'b', 2), (b'c', 3), (b'd', 3)]);
+        round_trip(&encoding_table, b"abacabadabacaba");
+        round_trip(&encoding_table, b"dddddddddd");
+    }
+
+    #[test]
+    fn decodes_empty_and_single_symbol_input() {
+        round_trip(&HashMap::new(), b"");
+        round_trip(&table(&[(b'a', 1), (b'b', 1)]), b"");
+        round_trip(&table(&[(b'x', 1)]), b"x");
+        round_trip(&table(&[(b'x', 1)]), &[b'x'; 1000]);
+    }
+
+    #[test]
+    fn decodes_codes_longer_than_the_lookup_table() {
+        // Lengths 1..=23 with 23 twice, so most symbols need the long code fallback
+        let mut lengths: Vec<(u8, u8)> = (1..23).map(|length| (length, length)).collect();
+        lengths.push((23, 23));
+        lengths.push((0, 23));
+        let encoding_table = table(&lengths);
+        assert!(encoding_table.values().any(|code| code.len() > LOOKUP_BITS as usize));
+
+        let data: Vec<u8> = (0..24).chain((0..24).rev()).chain([0, 23, 0, 1, 22]).collect();
+        round_trip(&encoding_table, &data);
+    }
+
+    #[test]
+    fn decodes_64_bit_codes() {
+        // Lengths 1..=64 with 64 twice, the most skewed dictionary Dictionary::parse accepts
+        let mut lengths: Vec<(u8, u8)> = (1..=64).map(|length| (length, length)).collect();
+        lengths.push((0, 64));
+        let dictionary = Dictionary::from_encoding_table(&table(&lengths)).unwrap();
+        let encoding_table = Dictionary::parse(&dictionary.to_bytes()).unwrap().encoding_table();
+        assert_eq!(encoding_table[&0].len(), 64);
+
+        // Codes of every length in between leave the 64 bit ones at every offset in the buffer
+        let data: Vec<u8> = (0..=64).flat_map(|symbol| [symbol, 64, 1, 0, 63]).collect();
+        round_trip(&encoding_table, &data);
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        let mut encoding_table = HashMap::new();
+        encoding_table.insert(b'a', String::new());
+        assert!(DecodeTable::new(&encoding_table).is_err());
+        encoding_table.insert(b'a', "012".into());
+        assert!(DecodeTable::new(&encoding_table).is_err());
+        encoding_table.insert(b'a', "1".repeat(65));
+        assert!(DecodeTable::new(&encoding_table).is_err());
+    }
+
+    #[test]
+    fn rejects_damaged_data() {
+        let encoding_table = table(&[(b'a', 1), (b'b', 2), (b'c', 3), (b'd', 3)]);
+        let decode_table = DecodeTable::new(&encoding_table).unwrap();
+        let packed = encode(&encoding_table, b"abcdabcd");
+
+        assert!(decode_table.decode(&packed[..PACKED_HEADER_LEN - 1]).is_err());
+        // Cut short in the middle of the stream
+        let mut cut = packed[..packed.len() - 1].to_vec();
+        cut[0] = 0;
+        assert!(decode_table.decode(&cut).is_err());
+        // More symbols claimed than bits follow
+        let mut too_many = packed.clone();
+        too_many[1..PACKED_HEADER_LEN].copy_from_slice(&1000u64.to_be_bytes());
+        assert!(decode_table.decode(&too_many).is_err());
+        // Fewer symbols claimed than bits follow
+        let mut too_few = packed.clone();
+        too_few[1..PACKED_HEADER_LEN].copy_from_slice(&7u64.to_be_bytes());
+        assert!(decode_table.decode(&too_few).unwrap_err().contains("left over"));
+        let mut bad_padding = packed.clone();
+        bad_padding[0] = 8;
+        assert!(decode_table.decode(&bad_padding).is_err());
+        assert!(decode_table.decode(&pack_bits(&[], 0)).unwrap().is_empty());
+
+        // A bit pattern no code starts with
+        let incomplete = table(&[(b'a', 1)]);
+        let decode_table = DecodeTable::new(&incomplete).unwrap();
+        assert!(decode_table.decode(&pack_bits(&[1], 1)).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::checksum::crc32;
+
+// On-disk layout of a dictionary file (all integers big-endian):
+//
+//   magic        4 bytes   "DSDC"
+//   version      1 byte    DICTIONARY_VERSION
+//   entry count  2 bytes
+//   entries      2 bytes each: symbol (byte value) followed by its code length
+//   checksum     4 bytes   CRC-32 of everything before it
+//
+// Only the code lengths are stored. The codes themselves are canonical Huffman codes,
+// so any machine can rebuild the exact same table from the lengths alone.
+pub const DICTIONARY_MAGIC: &[u8; 4] = b"DSDC";
+pub const DICTIONARY_VERSION: u8 = 2;
+const MAX_CODE_LENGTH: u8 = 64;
+const HEADER_LEN: usize = 4 + 1 + 2;
+const ENTRY_LEN: usize = 1 + 1;
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    code_lengths: BTreeMap<u8, u8>,
+}
+
+impl Dictionary {
+    pub fn from_encoding_table(encoding_table: &HashMap<u8, String>) -> Result<Dictionary, String> {
+        let mut code_lengths = BTreeMap::new();
+        for (&symbol, code) in encoding_table {
+            if code.len() > MAX_CODE_LENGTH as usize {
+                return Err(format!("Code for byte {:#04x} is longer than {} bits", symbol, MAX_CODE_LENGTH));
+            }
+            code_lengths.insert(symbol, code.len() as u8);
+        }
+        Ok(Dictionary { code_lengths })
+    }
+
+    // Rebuild the canonical encoding table described by the stored code lengths
+    pub fn encoding_table(&self) -> HashMap<u8, String> {
+        canonical_codes(&self.code_lengths)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.code_lengths.len() * ENTRY_LEN + CHECKSUM_LEN);
+        bytes.extend_from_slice(DICTIONARY_MAGIC);
+        bytes.push(DICTIONARY_VERSION);
+        bytes.extend_from_slice(&(self.code_lengths.len() as u16).to_be_bytes());
+
+        for (&symbol, &length) in &self.code_lengths {
+            bytes.push(symbol);
+            bytes.push(length);
+        }
+
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Dictionary, String> {
+        if bytes.len() < HEADER_LEN + CHECKSUM_LEN {
+            return Err("Dictionary is too short".into());
+        }
+        if &bytes[..4] != DICTIONARY_MAGIC {
+            return Err("Not a dictionary file".into());
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        let expected_checksum = u32::from_be_bytes(checksum.try_into().unwrap());
+        if crc32(body) != expected_checksum {
+            return Err("Dictionary checksum mismatch".into());
+        }
+
+        let version = body[4];
+        if version != DICTIONARY_VERSION {
+            return Err(format!("Unsupported dictionary version {}", version));
+        }
+
+        let entry_count = u16::from_be_bytes(body[5..7].try_into().unwrap()) as usize;
+        let entries = &body[HEADER_LEN..];
+        if entries.len() != entry_count * ENTRY_LEN {
+            return Err(format!("Dictionary declares {} entries but holds {} bytes of entries", entry_count, entries.len()));
+        }
+
+        let mut code_lengths = BTreeMap::new();
+        for entry in entries.chunks(ENTRY_LEN) {
+            let (symbol, length) = (entry[0], entry[1]);
+            if length > MAX_CODE_LENGTH {
+                return Err(format!("Code length {} for byte {:#04x} is too long", length, symbol));
+            }
+            if code_lengths.insert(symbol, length).is_some() {
+                return Err(format!("Byte {:#04x} appears twice in dictionary", symbol));
+            }
+        }
+
+        validate_code_lengths(&code_lengths)?;
+        Ok(Dictionary { code_lengths })
+    }
+}
+
+// The lengths must describe a complete prefix code, otherwise the canonical codes
+// would overlap or leave bit patterns that decode to nothing.
+fn validate_code_lengths(code_lengths: &BTreeMap<u8, u8>) -> Result<(), String> {
+    // A single symbol input is encoded with a one bit code
+    if code_lengths.len() == 1 {
+        return match code_lengths.values().next() {
+            Some(1) => Ok(()),
+            _ => Err("A lone symbol must have a one bit code".into()),
+        };
+    }
+
+    // Kraft sum scaled by 2^MAX_CODE_LENGTH, must be exactly one
+    let mut kraft_sum: u128 = 0;
+    for (&symbol, &length) in code_lengths {
+        if length == 0 {
+            return Err(format!("Byte {:#04x} has an empty code", symbol));
+        }
+        kraft_sum += 1u128 << (MAX_CODE_LENGTH - length);
+    }
+    if !code_lengths.is_empty() && kraft_sum != 1u128 << MAX_CODE_LENGTH {
+        return Err("Code lengths do not form a complete prefix code".into());
+    }
+    Ok(())
+}
+
+// Assign canonical Huffman codes: symbols are ordered by (code length, symbol) and each
+// one gets the previous code plus one, shifted left whenever the length grows.
+pub fn canonical_codes(code_lengths: &BTreeMap<u8, u8>) -> HashMap<u8, String> {
+    let mut ordered: Vec<(u8, u8)> = code_lengths.iter().map(|(&symbol, &length)| (length, symbol)).collect();
+    ordered.sort();
+
+    let mut codes = HashMap::new();
+    let mut code: u64 = 0;
+    let mut previous_length = 0;
+    for (index, (length, symbol)) in ordered.into_iter().enumerate() {
+        if index > 0 {
+            code += 1;
+        }
+        code = code.checked_shl((length - previous_length) as u32).unwrap_or(0);
+        previous_length = length;
+
+        let bits = (0..length).rev().map(|i| if (code >> i) & 1 == 1 { '1' } else { '0' }).collect();
+        codes.insert(symbol, bits);
+    }
+    codes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lengths(pairs: &[(u8, u8)]) -> BTreeMap<u8, u8> {
+        pairs.iter().copied().collect()
+    }
+
+    // Recompute the checksum after editing the body, so parse gets past it
+    fn reseal(mut bytes: Vec<u8>) -> Vec<u8> {
+        bytes.truncate(bytes.len() - CHECKSUM_LEN);
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    // Lengths 1, 2, .., longest - 1 and longest twice: a complete code with very long codes
+    fn skewed(longest: u8) -> BTreeMap<u8, u8> {
+        let mut code_lengths: BTreeMap<u8, u8> = (1..longest).map(|length| (length, length)).collect();
+        code_lengths.insert(longest, longest);
+        code_lengths.insert(0, longest);
+        code_lengths
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        for code_lengths in [lengths(&[]), lengths(&[(b'x', 1)]), lengths(&[(b'a', 1), (b'b', 2), (b'c', 3), (b'd', 3)]), skewed(MAX_CODE_LENGTH)] {
+            let dictionary = Dictionary { code_lengths };
+            let bytes = dictionary.to_bytes();
+            assert_eq!(Dictionary::parse(&bytes).unwrap(), dictionary);
+            assert_eq!(Dictionary::from_encoding_table(&dictionary.encoding_table()).unwrap(), dictionary);
+        }
+    }
+
+    #[test]
+    fn canonical_codes_are_prefix_free() {
+        for code_lengths in [lengths(&[(b'a', 1), (b'b', 2), (b'c', 3), (b'd', 3)]), skewed(MAX_CODE_LENGTH)] {
+            let codes = canonical_codes(&code_lengths);
+            for (symbol, code) in &codes {
+                assert_eq!(code.len(), code_lengths[symbol] as usize);
+                for (other, other_code) in &codes {
+                    assert!(symbol == other || !other_code.starts_with(code.as_str()), "{} is a prefix of {}", code, other_code);
+                }
+            }
+        }
+        let codes = canonical_codes(&lengths(&[(b'a', 1), (b'b', 2), (b'c', 3), (b'd', 3)]));
+        assert_eq!(codes[&b'a'], "0");
+        assert_eq!(codes[&b'b'], "10");
+        assert_eq!(codes[&b'c'], "110");
+        assert_eq!(codes[&b'd'], "111");
+    }
+
+    #[test]
+    fn checks_the_kraft_sum() {
+        assert!(validate_code_lengths(&lengths(&[])).is_ok());
+        assert!(validate_code_lengths(&lengths(&[(b'x', 1)])).is_ok());
+        assert!(validate_code_lengths(&lengths(&[(b'x', 2)])).is_err());
+        assert!(validate_code_lengths(&lengths(&[(b'a', 1), (b'b', 1)])).is_ok());
+        // Incomplete: 1/2 + 1/4 leaves patterns that decode to nothing
+        assert!(validate_code_lengths(&lengths(&[(b'a', 1), (b'b', 2)])).is_err());
+        // Over-full: codes would overlap
+        assert!(validate_code_lengths(&lengths(&[(b'a', 1), (b'b', 1), (b'c', 2)])).is_err());
+        assert!(validate_code_lengths(&lengths(&[(b'a', 0), (b'b', 1)])).is_err());
+
+        let incomplete = Dictionary { code_lengths: lengths(&[(b'a', 1), (b'b', 2)]) };
+        assert!(Dictionary::parse(&incomplete.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let bytes = Dictionary { code_lengths: lengths(&[(b'a', 1), (b'b', 2), (b'c', 2)]) }.to_bytes();
+
+        for position in [0, 4, 6, bytes.len() - 1] {
+            let mut flipped = bytes.clone();
+            flipped[position] ^= 0x10;
+            assert!(Dictionary::parse(&flipped).is_err());
+        }
+        let mut flipped = bytes.clone();
+        flipped[HEADER_LEN + 1] ^= 0x01;
+        assert_eq!(Dictionary::parse(&flipped).unwrap_err(), "Dictionary checksum mismatch");
+        assert_eq!(Dictionary::parse(&bytes[..HEADER_LEN]).unwrap_err(), "Dictionary is too short");
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = DICTIONARY_VERSION + 1;
+        assert!(Dictionary::parse(&reseal(wrong_version)).unwrap_err().contains("Unsupported dictionary version"));
+
+        let mut wrong_count = bytes.clone();
+        wrong_count[6] = 4;
+        assert!(Dictionary::parse(&reseal(wrong_count)).unwrap_err().contains("declares 4 entries"));
+
+        let mut duplicate = bytes.clone();
+        duplicate[HEADER_LEN + ENTRY_LEN] = b'a';
+        assert!(Dictionary::parse(&reseal(duplicate)).unwrap_err().contains("appears twice"));
+
+        let mut too_long = bytes.clone();
+        too_long[HEADER_LEN + 1] = MAX_CODE_LENGTH + 1;
+        assert!(Dictionary::parse(&reseal(too_long)).unwrap_err().contains("too long"));
+    }
+
+    #[test]
+    fn refuses_codes_over_the_limit() {
+        let mut encoding_table = HashMap::new();
+        encoding_table.insert(b'a', "0".repeat(MAX_CODE_LENGTH as usize + 1));
+        assert!(Dictionary::from_encoding_table(&encoding_table).is_err());
+    }
+}
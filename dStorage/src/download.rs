@@ -0,0 +1,376 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::chunk_store::sha256_hex;
+
+// Files are downloaded in ranges: a Download message names an offset and a length, and
+// the Ack that answers it carries a RangeReply. Every reply repeats the size and SHA-256
+// of the whole file, so a client can plan the remaining ranges after the first one, spread
+// them over several replicas, pick up an interrupted transfer at the first range it is
+// missing, and check the reassembled file against the hash at the end.
+//
+// The node serving a range checks it against hashes recorded at upload, one per BLOCK_LEN
+// block of the file, so corruption on its disk is reported as such instead of being sent.
+//
+// RangeReply layout (integers big-endian):
+//
+//   file size   8 bytes
+//   hash len    2 bytes, then the SHA-256 of the file as lowercase hex
+//   data        the rest of the payload
+
+// Longest range served in one reply, well under the frame payload limit
+pub const MAX_RANGE_LEN: u32 = 8 * 1024 * 1024;
+
+// Size of the blocks a stored file is hashed in
+pub const BLOCK_LEN: u64 = 1024 * 1024;
+
+// Hashes kept next to a stored file: the SHA-256 of the whole file on the first line, then
+// one line per block. Files stored before blocks were hashed only have the first line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredHashes {
+    pub file_hash: String,
+    pub block_hashes: Vec<String>,
+}
+
+impl StoredHashes {
+    pub fn compute<R: Read>(reader: &mut R) -> io::Result<StoredHashes> {
+        let mut file_hasher = Sha256::new();
+        let mut block_hashes = Vec::new();
+        let mut block = Vec::with_capacity(BLOCK_LEN as usize);
+        loop {
+            block.clear();
+            reader.by_ref().take(BLOCK_LEN).read_to_end(&mut block)?;
+            if block.is_empty() {
+                break;
+            }
+            file_hasher.update(&block);
+            block_hashes.push(sha256_hex(&block));
+        }
+        Ok(StoredHashes { file_hash: format!("{:x}", file_hasher.finalize()), block_hashes })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = self.file_hash.clone();
+        for hash in &self.block_hashes {
+            text.push('\n');
+            text.push_str(hash);
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<StoredHashes, String> {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        let file_hash = lines.next().ok_or("Hash file is empty")?.to_string();
+        Ok(StoredHashes { file_hash, block_hashes: lines.map(String::from).collect() })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeReply {
+    pub file_size: u64,
+    pub file_hash: String,
+    pub data: Vec<u8>,
+}
+
+impl RangeReply {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + 2 + self.file_hash.len() + self.data.len());
+        bytes.extend_from_slice(&self.file_size.to_be_bytes());
+        bytes.extend_from_slice(&(self.file_hash.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.file_hash.as_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<RangeReply, String> {
+        if bytes.len() < 10 {
+            return Err("Range reply is cut short".into());
+        }
+        let file_size = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+        let hash_len = u16::from_be_bytes(bytes[8..10].try_into().unwrap()) as usize;
+        let hash = bytes.get(10..10 + hash_len).ok_or("Range reply is cut short")?;
+        let file_hash = String::from_utf8(hash.to_vec()).map_err(|_| "Range reply hash is not UTF-8".to_string())?;
+        Ok(RangeReply { file_size, file_hash, data: bytes[10 + hash_len..].to_vec() })
+    }
+}
+
+// Read up to length bytes of the file at path starting at offset, capped at MAX_RANGE_LEN,
+// after checking the blocks they fall in against hashes. Returns the size of the whole
+// file with the bytes read; an offset at the end of the file gives no bytes, one past it
+// is an error. A mismatch is an InvalidData error naming the bytes that are off.
+pub fn read_range(path: &Path, offset: u64, length: u32, hashes: &StoredHashes) -> io::Result<(u64, Vec<u8>)> {
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    if offset > file_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Offset {} is past the end of the file ({} bytes)", offset, file_size),
+        ));
+    }
+    let length = u64::from(length.min(MAX_RANGE_LEN)).min(file_size - offset);
+
+    // Without block hashes the only check left is hashing the whole file
+    if hashes.block_hashes.is_empty() {
+        let actual = crate::chunk_store::sha256_hex_reader(&mut file)?;
+        if actual != hashes.file_hash {
+            return Err(corrupt(0, file_size, &hashes.file_hash, &actual));
+        }
+        let mut data = Vec::with_capacity(length as usize);
+        file.seek(SeekFrom::Start(offset))?;
+        file.take(length).read_to_end(&mut data)?;
+        return check_length(offset, data, length, file_size);
+    }
+
+    if hashes.block_hashes.len() as u64 != file_size.div_ceil(BLOCK_LEN) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} block hashes recorded for a file of {} bytes", hashes.block_hashes.len(), file_size),
+        ));
+    }
+    if length == 0 {
+        return Ok((file_size, Vec::new()));
+    }
+
+    // Read every block the range touches, check it and keep the part that was asked for
+    let first_block = offset / BLOCK_LEN;
+    let last_block = (offset + length - 1) / BLOCK_LEN;
+    let mut data = Vec::with_capacity(length as usize);
+    let mut block = Vec::with_capacity(BLOCK_LEN as usize);
+    file.seek(SeekFrom::Start(first_block * BLOCK_LEN))?;
+    for index in first_block..=last_block {
+        let block_start = index * BLOCK_LEN;
+        block.clear();
+        (&mut file).take(BLOCK_LEN).read_to_end(&mut block)?;
+        let actual = sha256_hex(&block);
+        if actual != hashes.block_hashes[index as usize] {
+            return Err(corrupt(block_start, block_start + block.len() as u64, &hashes.block_hashes[index as usize], &actual));
+        }
+        let start = offset.max(block_start) - block_start;
+        let end = (offset + length).min(block_start + block.len() as u64) - block_start;
+        data.extend_from_slice(&block[start as usize..end as usize]);
+    }
+    check_length(offset, data, length, file_size)
+}
+
+fn corrupt(start: u64, end: u64, expected: &str, actual: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bytes {}..{} hash to {} instead of {}", start, end, actual, expected),
+    )
+}
+
+fn check_length(offset: u64, data: Vec<u8>, length: u64, file_size: u64) -> io::Result<(u64, Vec<u8>)> {
+    if data.len() as u64 != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("File shrank while bytes {}..{} were being read", offset, offset + length),
+        ));
+    }
+    Ok((file_size, data))
+}
+
+// Ranges of a file still to be fetched. New ranges are only cut from the part nobody has
+// asked for yet when a worker wants one, so the size a replica announces costs no memory
+// up front; ranges that failed wait in retry. Ranges handed out are counted as in flight
+// until done is called, a worker that finds the queue empty while others still have
+// ranges in flight has to wait, as any of them may fail and come back.
+#[derive(Debug)]
+pub struct RangeQueue {
+    next_offset: u64,
+    file_size: u64,
+    range_len: u32,
+    retry: Vec<(u64, u32)>,
+    in_flight: usize,
+}
+
+impl RangeQueue {
+    pub fn new(start: u64, file_size: u64, range_len: u32) -> RangeQueue {
+        RangeQueue { next_offset: start.min(file_size), file_size, range_len: range_len.max(1), retry: Vec::new(), in_flight: 0 }
+    }
+
+    // Queue what is left of a file once the ranges in received (offset and length) are
+    // already on disk: the gaps between them, then the tail after the last one
+    pub fn resume(received: &[(u64, u64)], file_size: u64, range_len: u32) -> RangeQueue {
+        let mut received: Vec<(u64, u64)> = received.iter().map(|&(offset, length)| (offset.min(file_size), offset.saturating_add(length).min(file_size))).collect();
+        received.sort_unstable();
+        let mut queue = RangeQueue::new(0, file_size, range_len);
+        for (start, end) in received {
+            if start > queue.next_offset {
+                queue.skip_to(start);
+            }
+            queue.next_offset = queue.next_offset.max(end);
+        }
+        queue
+    }
+
+    // Move the untouched part on to offset, handing back what it passes over as retries
+    fn skip_to(&mut self, offset: u64) {
+        while self.next_offset < offset {
+            let length = u64::from(self.range_len).min(offset - self.next_offset) as u32;
+            self.retry.push((self.next_offset, length));
+            self.next_offset += u64::from(length);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<(u64, u32)> {
+        let range = match self.retry.pop() {
+            Some(range) => range,
+            None if self.next_offset >= self.file_size => return None,
+            None => {
+                let length = u64::from(self.range_len).min(self.file_size - self.next_offset) as u32;
+                let range = (self.next_offset, length);
+                self.next_offset += u64::from(length);
+                range
+            }
+        };
+        self.in_flight += 1;
+        Some(range)
+    }
+
+    // A range from pop was dealt with, whether it arrived or was pushed back
+    pub fn done(&mut self) {
+        self.in_flight -= 1;
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    // Hand a range back to be fetched again
+    pub fn push(&mut self, offset: u64, length: u32) {
+        self.retry.push((offset, length));
+    }
+
+    // Number of ranges left, counting the untouched tail in range_len pieces
+    pub fn len(&self) -> u64 {
+        self.retry.len() as u64 + (self.file_size - self.next_offset).div_ceil(u64::from(self.range_len))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Ranges of a download already written to its .part file, kept next to it so an
+// interrupted download resumes with what is missing. The first line names the size and
+// hash of the file being downloaded, then there is one "offset length" line per range
+// written. A record the .part file doesn't back up only costs a failed hash check at
+// the end, after which the download starts over.
+pub struct DownloadProgress {
+    file: File,
+}
+
+impl DownloadProgress {
+    // Pick up the progress at path if it is for the same version of the file, or start it
+    // over. Returns it with the ranges already received.
+    pub fn open(path: &Path, file_size: u64, file_hash: &str) -> io::Result<(DownloadProgress, Vec<(u64, u64)>)> {
+        let header = format!("{} {}", file_size, file_hash);
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut lines = text.lines();
+        if lines.next() == Some(header.as_str()) {
+            // A line cut short by an interrupted write is skipped
+            let received = lines.filter_map(|line| {
+                let (offset, length) = line.split_once(' ')?;
+                Some((offset.parse().ok()?, length.parse().ok()?))
+            });
+            let received = received.collect();
+            let file = OpenOptions::new().append(true).open(path)?;
+            return Ok((DownloadProgress { file }, received));
+        }
+        let mut file = File::create(path)?;
+        file.write_all(format!("{}\n", header).as_bytes())?;
+        Ok((DownloadProgress { file }, Vec::new()))
+    }
+
+    pub fn record(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.file.write_all(format!("{} {}\n", offset, length).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn stored_file(name: &str, data: &[u8]) -> (PathBuf, StoredHashes) {
+        let path = std::env::temp_dir().join(format!("dstorage-range-{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        (path, StoredHashes::compute(&mut &data[..]).unwrap())
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn reads_ranges_across_blocks() {
+        let data = sample(3 * BLOCK_LEN as usize + 100);
+        let (path, hashes) = stored_file("blocks", &data);
+        assert_eq!(hashes.block_hashes.len(), 4);
+        assert_eq!(StoredHashes::parse(&hashes.to_text()).unwrap(), hashes);
+
+        let block = BLOCK_LEN as usize;
+        for (offset, length) in [(0, 10), (block - 5, 10), (block / 2, 2 * block as u32), (data.len() - 7, 100), (data.len(), 5)] {
+            let (file_size, range) = read_range(&path, offset as u64, length, &hashes).unwrap();
+            assert_eq!(file_size, data.len() as u64);
+            assert_eq!(range, data[offset..(offset + length as usize).min(data.len())]);
+        }
+        assert_eq!(read_range(&path, data.len() as u64 + 1, 1, &hashes).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reports_the_corrupt_block() {
+        let mut data = sample(2 * BLOCK_LEN as usize + 1);
+        let (path, hashes) = stored_file("corrupt", &data);
+        data[BLOCK_LEN as usize + 3] ^= 0x40;
+        fs::write(&path, &data).unwrap();
+
+        assert!(read_range(&path, 0, 100, &hashes).is_ok());
+        let error = read_range(&path, BLOCK_LEN - 1, 2, &hashes).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains(&format!("bytes {}..{}", BLOCK_LEN, 2 * BLOCK_LEN)));
+
+        // Files stored with only the file hash are checked as a whole
+        let legacy = StoredHashes { file_hash: hashes.file_hash.clone(), block_hashes: Vec::new() };
+        assert_eq!(read_range(&path, 0, 100, &legacy).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn range_queue_hands_out_the_tail_lazily_and_retries_first() {
+        let mut queue = RangeQueue::new(10, u64::MAX, 1024);
+        assert_eq!(queue.pop(), Some((10, 1024)));
+        assert_eq!(queue.pop(), Some((1034, 1024)));
+        queue.push(10, 1024);
+        queue.done();
+        assert_eq!(queue.in_flight(), 1);
+        assert_eq!(queue.pop(), Some((10, 1024)));
+
+        let mut queue = RangeQueue::new(0, 2500, 1000);
+        assert_eq!(queue.len(), 3);
+        let ranges: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(ranges, vec![(0, 1000), (1000, 1000), (2000, 500)]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.in_flight(), 3);
+    }
+
+    #[test]
+    fn range_queue_resumes_around_the_ranges_received() {
+        let mut queue = RangeQueue::resume(&[(2000, 1000), (0, 1000), (2500, 1000), (6000, 500)], 7000, 1000);
+        let mut ranges: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        ranges.sort_unstable();
+        assert_eq!(ranges, vec![(1000, 1000), (3500, 1000), (4500, 1000), (5500, 500), (6500, 500)]);
+
+        assert!(RangeQueue::resume(&[(0, 7000)], 7000, 1000).is_empty());
+        assert_eq!(RangeQueue::resume(&[], 2500, 1000).len(), 3);
+        // Ranges recorded past the end of the file don't count
+        assert_eq!(RangeQueue::resume(&[(9000, 1000)], 2500, 1000).len(), 3);
+    }
+}
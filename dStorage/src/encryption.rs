@@ -0,0 +1,441 @@
+use std::path::Path;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use crate::chunk_store::sha256_hex;
+
+// Client-side authenticated encryption of stored chunks. Chunks are encrypted after they
+// are encoded, so the peers hosting them only ever see ciphertext. Every file has its own
+// key, either derived from a passphrase with Argon2id and a per-file salt kept in the
+// manifest, or generated at random and kept in a local keyring that never leaves this node.
+//
+// An encrypted chunk is the random nonce (12 bytes) followed by the ChaCha20-Poly1305
+// ciphertext and tag. The chunk offset is authenticated along with it, so a host can't
+// swap chunks of the same file around.
+//
+// Random keys and nonces make every upload of the same data look different, which defeats
+// the content-addressed dedup of the chunk store. Convergent mode trades some privacy to
+// keep it: each chunk is encrypted with a key derived from its own content and a fixed
+// nonce, so identical chunks encrypt to identical ciphertext and are stored once, whoever
+// uploads them. The keys go into the local keyring under the hash of the stored chunk.
+// What convergent mode gives away:
+//
+//   - Anyone who can guess a chunk can encrypt it and check whether the network stores it,
+//     so it confirms the presence of known files (a leaked document, a popular binary) and
+//     can be used to brute-force files that differ from a known one in a few bytes
+//   - Peers can see that two files, or two users, share chunks
+//
+// The content of chunks nobody can guess stays confidential. Use passphrase or keyring
+// keys for files where either leak matters, convergent mode where storage space does.
+
+pub const KEY_LEN: usize = 32;
+pub const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const CONVERGENT_KEY_CONTEXT: &[u8] = b"dstorage convergent chunk key";
+
+// How the key of a file was obtained, recorded in its manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    None,
+    Passphrase { salt: [u8; SALT_LEN] },
+    Keyring,
+    Convergent,
+}
+
+impl Encryption {
+    pub const NONE_ID: u8 = 0;
+    pub const PASSPHRASE_ID: u8 = 1;
+    pub const KEYRING_ID: u8 = 2;
+    pub const CONVERGENT_ID: u8 = 3;
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Encryption::None => Encryption::NONE_ID,
+            Encryption::Passphrase { .. } => Encryption::PASSPHRASE_ID,
+            Encryption::Keyring => Encryption::KEYRING_ID,
+            Encryption::Convergent => Encryption::CONVERGENT_ID,
+        }
+    }
+
+    pub fn salt(&self) -> Option<&[u8; SALT_LEN]> {
+        match self {
+            Encryption::Passphrase { salt } => Some(salt),
+            _ => None,
+        }
+    }
+
+    pub fn from_parts(id: u8, salt: Option<&[u8]>) -> Result<Encryption, String> {
+        match id {
+            Encryption::NONE_ID => Ok(Encryption::None),
+            Encryption::PASSPHRASE_ID => {
+                let salt = salt
+                    .and_then(|salt| <[u8; SALT_LEN]>::try_from(salt).ok())
+                    .ok_or("Passphrase encryption needs a 16 byte salt")?;
+                Ok(Encryption::Passphrase { salt })
+            }
+            Encryption::KEYRING_ID => Ok(Encryption::Keyring),
+            Encryption::CONVERGENT_ID => Ok(Encryption::Convergent),
+            _ => Err(format!("Unknown encryption {}", id)),
+        }
+    }
+}
+
+// Where the key of a file comes from when uploading or downloading it
+pub enum KeySource<'a> {
+    Passphrase(&'a str),
+    Keyring(&'a Keyring),
+    Convergent(&'a Keyring),
+}
+
+#[derive(Clone)]
+pub struct FileKey([u8; KEY_LEN]);
+
+// Encrypts and decrypts the chunks of one file, with the file key or with per-chunk
+// convergent keys
+pub enum ChunkCipher<'a> {
+    File(FileKey),
+    Convergent(&'a Keyring),
+}
+
+impl<'a> ChunkCipher<'a> {
+    // Pick the keys of a new upload, returns them with what the manifest records.
+    // A new keyring key is only kept once keep_key is called, after the manifest is saved,
+    // so a failed upload doesn't replace the key of the copy already stored.
+    pub fn for_upload(source: &KeySource<'a>) -> Result<(Encryption, ChunkCipher<'a>), String> {
+        match source {
+            KeySource::Passphrase(passphrase) => {
+                let mut salt = [0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                let key = FileKey::from_passphrase(passphrase, &salt)?;
+                Ok((Encryption::Passphrase { salt }, ChunkCipher::File(key)))
+            }
+            KeySource::Keyring(_) => Ok((Encryption::Keyring, ChunkCipher::File(FileKey::random()))),
+            KeySource::Convergent(keyring) => Ok((Encryption::Convergent, ChunkCipher::Convergent(keyring))),
+        }
+    }
+
+    // Store the file key of an upload from the keyring once its manifest is saved. Keys
+    // from a passphrase are derived again and convergent keys are stored per chunk.
+    pub fn keep_key(&self, source: &KeySource<'a>, file_name: &str) -> Result<(), String> {
+        match (self, source) {
+            (ChunkCipher::File(key), KeySource::Keyring(keyring)) => {
+                keyring.store(file_name, key).map_err(|e| format!("Unable to store key of '{}': {}", file_name, e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Get back the keys a file was uploaded with, None when it isn't encrypted
+    pub fn for_download(source: Option<&KeySource<'a>>, file_name: &str, encryption: &Encryption) -> Result<Option<ChunkCipher<'a>>, String> {
+        match (encryption, source) {
+            (Encryption::None, _) => Ok(None),
+            (Encryption::Passphrase { salt }, Some(KeySource::Passphrase(passphrase))) => {
+                Ok(Some(ChunkCipher::File(FileKey::from_passphrase(passphrase, salt)?)))
+            }
+            (Encryption::Keyring, Some(KeySource::Keyring(keyring))) => keyring
+                .load(file_name)
+                .map_err(|e| format!("Unable to read key of '{}': {}", file_name, e))?
+                .map(|key| Some(ChunkCipher::File(key)))
+                .ok_or_else(|| format!("No key for '{}' in the keyring", file_name)),
+            (Encryption::Convergent, Some(KeySource::Keyring(keyring) | KeySource::Convergent(keyring))) => {
+                Ok(Some(ChunkCipher::Convergent(keyring)))
+            }
+            (Encryption::Passphrase { .. }, _) => Err(format!("'{}' is encrypted with a passphrase", file_name)),
+            (Encryption::Keyring | Encryption::Convergent, _) => Err(format!("'{}' is encrypted with keys from the keyring", file_name)),
+        }
+    }
+
+    pub fn encrypt_chunk(&self, offset: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            ChunkCipher::File(key) => Ok(key.encrypt_chunk(offset, data)),
+            ChunkCipher::Convergent(keyring) => {
+                let key = FileKey::convergent(data);
+                let encrypted = key.encrypt_convergent(data);
+                keyring
+                    .store_chunk_key(&sha256_hex(&encrypted), &key)
+                    .map_err(|e| format!("Unable to store chunk key: {}", e))?;
+                Ok(encrypted)
+            }
+        }
+    }
+
+    // hash is the hash of the stored chunk, which convergent keys are filed under
+    pub fn decrypt_chunk(&self, hash: &str, offset: u64, encrypted: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            ChunkCipher::File(key) => key.decrypt_chunk(offset, encrypted),
+            ChunkCipher::Convergent(keyring) => keyring
+                .load_chunk_key(hash)
+                .map_err(|e| format!("Unable to read chunk key: {}", e))?
+                .ok_or_else(|| format!("No key for chunk {} in the keyring", hash))?
+                .decrypt_convergent(encrypted),
+        }
+    }
+}
+
+impl FileKey {
+    pub fn from_passphrase(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<FileKey, String> {
+        let mut key = [0u8; KEY_LEN];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| format!("Unable to derive key: {}", e))?;
+        Ok(FileKey(key))
+    }
+
+    pub fn random() -> FileKey {
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        FileKey(key)
+    }
+
+    // Key of a chunk in convergent mode, only depends on the chunk itself
+    pub fn convergent(data: &[u8]) -> FileKey {
+        let mut hasher = Sha256::new();
+        hasher.update(CONVERGENT_KEY_CONTEXT);
+        hasher.update(data);
+        FileKey(hasher.finalize().into())
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+
+    pub fn encrypt_chunk(&self, offset: u64, data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: &offset.to_be_bytes() })
+            .expect("ChaCha20-Poly1305 encrypts any chunk that fits in memory");
+
+        let mut encrypted = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        encrypted
+    }
+
+    pub fn decrypt_chunk(&self, offset: u64, encrypted: &[u8]) -> Result<Vec<u8>, String> {
+        if encrypted.len() < NONCE_LEN {
+            return Err("Encrypted chunk is missing its nonce".into());
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &offset.to_be_bytes() })
+            .map_err(|_| format!("Chunk at offset {} doesn't decrypt with this key", offset))
+    }
+
+    // A convergent key only ever encrypts one plaintext, the chunk it was derived from, so
+    // a fixed nonce is safe and makes the ciphertext depend on nothing but the chunk
+    fn encrypt_convergent(&self, data: &[u8]) -> Vec<u8> {
+        self.cipher()
+            .encrypt(Nonce::from_slice(&[0u8; NONCE_LEN]), data)
+            .expect("ChaCha20-Poly1305 encrypts any chunk that fits in memory")
+    }
+
+    fn decrypt_convergent(&self, encrypted: &[u8]) -> Result<Vec<u8>, String> {
+        self.cipher()
+            .decrypt(Nonce::from_slice(&[0u8; NONCE_LEN]), encrypted)
+            .map_err(|_| "Chunk doesn't decrypt with its convergent key".to_string())
+    }
+}
+
+// A stored key of any other length means the keyring is damaged, which is an error rather
+// than a missing key
+impl FromSql for FileKey {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let key = value.as_blob()?;
+        <[u8; KEY_LEN]>::try_from(key)
+            .map(FileKey)
+            .map_err(|_| FromSqlError::InvalidBlobSize { expected_size: KEY_LEN, blob_size: key.len() })
+    }
+}
+
+// Random per-file keys, kept in their own database next to pointers.db. Unlike pointers.db
+// the keyring is never shared with other nodes.
+pub struct Keyring {
+    conn: Connection,
+}
+
+impl Keyring {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Keyring> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS file_keys (
+                fileName TEXT PRIMARY KEY,
+                key BLOB NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS chunk_keys (
+                hash TEXT PRIMARY KEY,
+                key BLOB NOT NULL
+            )",
+            [],
+        )?;
+        Ok(Keyring { conn })
+    }
+
+    pub fn store(&self, file_name: &str, key: &FileKey) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO file_keys (fileName, key) VALUES (?1, ?2)",
+            params![file_name, &key.0[..]],
+        )?;
+        Ok(())
+    }
+
+    pub fn load(&self, file_name: &str) -> rusqlite::Result<Option<FileKey>> {
+        self.conn
+            .query_row("SELECT key FROM file_keys WHERE fileName = ?1", params![file_name], |row| row.get(0))
+            .optional()
+    }
+
+    // Convergent keys are filed under the hash of the chunk they decrypt, shared by every
+    // file containing that chunk
+    pub fn store_chunk_key(&self, hash: &str, key: &FileKey) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO chunk_keys (hash, key) VALUES (?1, ?2)",
+            params![hash, &key.0[..]],
+        )?;
+        Ok(())
+    }
+
+    pub fn load_chunk_key(&self, hash: &str) -> rusqlite::Result<Option<FileKey>> {
+        self.conn
+            .query_row("SELECT key FROM chunk_keys WHERE hash = ?1", params![hash], |row| row.get(0))
+            .optional()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAG_LEN: usize = 16;
+
+    fn keyring() -> Keyring {
+        Keyring::open(":memory:").unwrap()
+    }
+
+    fn download_error(source: Option<&KeySource>, encryption: &Encryption) -> String {
+        match ChunkCipher::for_download(source, "report.txt", encryption) {
+            Ok(_) => panic!("Expected no key for {:?}", encryption),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip_and_reject_tampering() {
+        let key = FileKey::random();
+        let encrypted = key.encrypt_chunk(4096, b"secret chunk");
+        assert_eq!(encrypted.len(), NONCE_LEN + b"secret chunk".len() + TAG_LEN);
+        assert_eq!(key.decrypt_chunk(4096, &encrypted).unwrap(), b"secret chunk");
+        // Every encryption takes a fresh nonce
+        assert_ne!(key.encrypt_chunk(4096, b"secret chunk"), encrypted);
+
+        for position in [0, NONCE_LEN, NONCE_LEN + 3, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[position] ^= 0x01;
+            assert!(key.decrypt_chunk(4096, &tampered).is_err(), "byte {} flipped", position);
+        }
+        assert!(key.decrypt_chunk(4096, &encrypted[..encrypted.len() - 1]).is_err());
+        assert!(key.decrypt_chunk(4096, &encrypted[..NONCE_LEN - 1]).is_err());
+
+        // A chunk moved to another offset of the file, or decrypted with another key, is refused
+        assert!(key.decrypt_chunk(0, &encrypted).is_err());
+        assert!(FileKey::random().decrypt_chunk(4096, &encrypted).is_err());
+    }
+
+    #[test]
+    fn passphrase_keys_only_come_back_with_the_same_passphrase_and_salt() {
+        let source = KeySource::Passphrase("correct horse");
+        let (encryption, cipher) = ChunkCipher::for_upload(&source).unwrap();
+        let salt = *encryption.salt().unwrap();
+        let encrypted = cipher.encrypt_chunk(0, b"secret chunk").unwrap();
+
+        let cipher = ChunkCipher::for_download(Some(&source), "report.txt", &encryption).unwrap().unwrap();
+        assert_eq!(cipher.decrypt_chunk("", 0, &encrypted).unwrap(), b"secret chunk");
+        let wrong = ChunkCipher::for_download(Some(&KeySource::Passphrase("wrong horse")), "report.txt", &encryption).unwrap().unwrap();
+        assert!(wrong.decrypt_chunk("", 0, &encrypted).is_err());
+        let other_salt = FileKey::from_passphrase("correct horse", &[!salt[0]; SALT_LEN]).unwrap();
+        assert!(other_salt.decrypt_chunk(0, &encrypted).is_err());
+    }
+
+    #[test]
+    fn keyring_keeps_one_key_per_file() {
+        let keyring = keyring();
+        assert!(keyring.load("report.txt").unwrap().is_none());
+
+        let key = FileKey::random();
+        keyring.store("report.txt", &key).unwrap();
+        assert_eq!(keyring.load("report.txt").unwrap().unwrap().0, key.0);
+        let replacement = FileKey::random();
+        keyring.store("report.txt", &replacement).unwrap();
+        assert_eq!(keyring.load("report.txt").unwrap().unwrap().0, replacement.0);
+        assert!(keyring.load("other.txt").unwrap().is_none());
+
+        // A damaged key is an error, not a missing key
+        keyring
+            .conn
+            .execute("INSERT INTO file_keys (fileName, key) VALUES ('short.txt', ?1)", params![&[7u8; 16][..]])
+            .unwrap();
+        assert!(keyring.load("short.txt").is_err());
+    }
+
+    #[test]
+    fn downloads_need_the_key_source_the_file_was_uploaded_with() {
+        let keyring = keyring();
+        let source = KeySource::Keyring(&keyring);
+        let (encryption, cipher) = ChunkCipher::for_upload(&source).unwrap();
+        assert_eq!(encryption, Encryption::Keyring);
+        let encrypted = cipher.encrypt_chunk(0, b"secret chunk").unwrap();
+
+        // The key is only in the keyring once the upload keeps it
+        assert!(download_error(Some(&source), &encryption).contains("No key"));
+        cipher.keep_key(&source, "report.txt").unwrap();
+        let cipher = ChunkCipher::for_download(Some(&source), "report.txt", &encryption).unwrap().unwrap();
+        assert_eq!(cipher.decrypt_chunk("", 0, &encrypted).unwrap(), b"secret chunk");
+
+        assert!(ChunkCipher::for_download(None, "report.txt", &Encryption::None).unwrap().is_none());
+        let passphrase = Encryption::Passphrase { salt: [1; SALT_LEN] };
+        assert!(download_error(None, &passphrase).contains("encrypted with a passphrase"));
+        assert!(download_error(Some(&source), &passphrase).contains("encrypted with a passphrase"));
+        for encryption in [Encryption::Keyring, Encryption::Convergent] {
+            assert!(download_error(None, &encryption).contains("keys from the keyring"));
+            assert!(download_error(Some(&KeySource::Passphrase("correct horse")), &encryption).contains("keys from the keyring"));
+        }
+    }
+
+    #[test]
+    fn convergent_chunks_encrypt_the_same_wherever_they_are() {
+        let keyring = keyring();
+        let cipher = ChunkCipher::Convergent(&keyring);
+        let first = cipher.encrypt_chunk(0, b"shared chunk").unwrap();
+        let second = cipher.encrypt_chunk(65536, b"shared chunk").unwrap();
+        assert_eq!(first, second);
+        let hash = sha256_hex(&first);
+        assert_eq!(hash, sha256_hex(&second));
+
+        // The keyring files the content-derived key under the stored chunk's hash
+        assert_eq!(keyring.load_chunk_key(&hash).unwrap().unwrap().0, FileKey::convergent(b"shared chunk").0);
+        assert_eq!(cipher.decrypt_chunk(&hash, 65536, &first).unwrap(), b"shared chunk");
+        assert!(cipher.decrypt_chunk(&sha256_hex(b"unknown"), 0, &first).unwrap_err().contains("No key"));
+        assert_ne!(cipher.encrypt_chunk(0, b"other chunk").unwrap(), first);
+
+        // A per-file key never gives the same ciphertext twice
+        let file = ChunkCipher::File(FileKey::random());
+        assert_ne!(file.encrypt_chunk(0, b"shared chunk").unwrap(), file.encrypt_chunk(0, b"shared chunk").unwrap());
+        assert_ne!(file.encrypt_chunk(0, b"shared chunk").unwrap(), first);
+
+        keyring
+            .conn
+            .execute("INSERT INTO chunk_keys (hash, key) VALUES ('short', ?1)", params![&[7u8; 16][..]])
+            .unwrap();
+        assert!(keyring.load_chunk_key("short").is_err());
+    }
+}
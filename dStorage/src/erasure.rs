@@ -0,0 +1,220 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+// Reed-Solomon erasure coding over stripes of chunks. Every stripe of data_shards chunks
+// gets parity_shards extra shards, and any data_shards of the data_shards + parity_shards
+// shards are enough to rebuild the whole stripe. Chunks in a stripe don't need to be the
+// same length, they are zero-padded to the longest one for the parity computation.
+//
+// A short last stripe is filled up with empty chunks, which are known to be all zeros and
+// never lost. It only keeps the first parity_shards_for(chunks) parity shards, so it can
+// lose as many of its shards, in proportion to a full stripe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureLayout {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+}
+
+impl ErasureLayout {
+    // A full stripe survives losing any two of its six shards and takes 1.5x the size of
+    // its chunks, before replication. A short last stripe takes more: a single chunk gets
+    // one parity shard, so a file of one chunk takes 2x.
+    pub const DEFAULT: ErasureLayout = ErasureLayout {
+        data_shards: 4,
+        parity_shards: 2,
+    };
+
+    fn reed_solomon(&self) -> Result<ReedSolomon, String> {
+        ReedSolomon::new(self.data_shards, self.parity_shards).map_err(|e| format!("Invalid erasure layout: {:?}", e))
+    }
+
+    pub fn stripe_count(&self, chunk_count: usize) -> usize {
+        chunk_count.div_ceil(self.data_shards)
+    }
+
+    // Parity shards kept for a stripe of chunk_count chunks, at least one
+    pub fn parity_shards_for(&self, chunk_count: usize) -> usize {
+        (chunk_count * self.parity_shards).div_ceil(self.data_shards).max(1).min(self.parity_shards)
+    }
+
+    // Parity shards of a file of chunk_count chunks. Only the last stripe can be short, so
+    // the parity of stripe s starts at s * parity_shards.
+    pub fn parity_count(&self, chunk_count: usize) -> usize {
+        let full_stripes = chunk_count / self.data_shards;
+        let rest = chunk_count % self.data_shards;
+        full_stripes * self.parity_shards + if rest > 0 { self.parity_shards_for(rest) } else { 0 }
+    }
+
+    // Compute the parity shards of one stripe, parity_shards_for(data.len()) of them
+    pub fn parity(&self, data: &[&[u8]]) -> Result<Vec<Vec<u8>>, String> {
+        if data.is_empty() || data.len() > self.data_shards {
+            return Err(format!("A stripe holds 1 to {} chunks, got {}", self.data_shards, data.len()));
+        }
+        let shard_size = data.iter().map(|chunk| chunk.len()).max().unwrap_or(0).max(1);
+
+        let mut shards: Vec<Vec<u8>> = Vec::with_capacity(self.data_shards + self.parity_shards);
+        for i in 0..self.data_shards {
+            let mut shard = data.get(i).map(|chunk| chunk.to_vec()).unwrap_or_default();
+            shard.resize(shard_size, 0);
+            shards.push(shard);
+        }
+        shards.resize(self.data_shards + self.parity_shards, vec![0u8; shard_size]);
+
+        self.reed_solomon()?.encode(&mut shards).map_err(|e| format!("Unable to compute parity: {:?}", e))?;
+        let mut parity = shards.split_off(self.data_shards);
+        parity.truncate(self.parity_shards_for(data.len()));
+        Ok(parity)
+    }
+
+    // Rebuild the chunks of a stripe. data holds the chunks that could be fetched (None for
+    // missing ones), data_lengths the real length of every chunk and parity the parity
+    // shards kept for the stripe (None for the ones that couldn't be fetched). Returns every
+    // chunk of the stripe at its real length.
+    pub fn reconstruct(&self, data: Vec<Option<Vec<u8>>>, data_lengths: &[usize], parity: Vec<Option<Vec<u8>>>) -> Result<Vec<Vec<u8>>, String> {
+        if data.len() != data_lengths.len() || data.is_empty() || data.len() > self.data_shards || parity.len() != self.parity_shards_for(data.len()) {
+            return Err("Stripe doesn't match the erasure layout".into());
+        }
+        let shard_size = data_lengths.iter().copied().max().unwrap_or(0).max(1);
+        let chunk_count = data.len();
+
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(self.data_shards + self.parity_shards);
+        for chunk in data {
+            shards.push(chunk.map(|mut chunk| {
+                chunk.resize(shard_size, 0);
+                chunk
+            }));
+        }
+        // Padding chunks of a short stripe are known to be all zeros
+        shards.resize(self.data_shards, Some(vec![0u8; shard_size]));
+        for shard in parity {
+            if shard.as_ref().is_some_and(|shard| shard.len() != shard_size) {
+                return Err("Parity shard has the wrong size".into());
+            }
+            shards.push(shard);
+        }
+        // Parity shards a short stripe doesn't keep
+        shards.resize(self.data_shards + self.parity_shards, None);
+
+        let available = shards.iter().filter(|shard| shard.is_some()).count();
+        if available < self.data_shards {
+            return Err(format!("Only {} of the {} shards needed to rebuild the stripe are available", available, self.data_shards));
+        }
+        self.reed_solomon()?
+            .reconstruct_data(&mut shards)
+            .map_err(|e| format!("Unable to rebuild stripe: {:?}", e))?;
+
+        Ok(shards
+            .into_iter()
+            .take(chunk_count)
+            .zip(data_lengths)
+            .map(|(shard, &length)| {
+                let mut chunk = shard.expect("reconstruct_data fills in every data shard");
+                chunk.truncate(length);
+                chunk
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Chunks of uneven lengths, like encoded chunks are
+    fn stripe(count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|i| (0..100 + i * 37).map(|j| (i * 31 + j * 7) as u8).collect()).collect()
+    }
+
+    fn encode(layout: ErasureLayout, chunks: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let refs: Vec<&[u8]> = chunks.iter().map(|chunk| chunk.as_slice()).collect();
+        layout.parity(&refs).unwrap()
+    }
+
+    fn lengths(chunks: &[Vec<u8>]) -> Vec<usize> {
+        chunks.iter().map(|chunk| chunk.len()).collect()
+    }
+
+    #[test]
+    fn parity_scales_with_the_stripe() {
+        let layout = ErasureLayout::DEFAULT;
+        let counts: Vec<usize> = (1..=4).map(|chunks| layout.parity_shards_for(chunks)).collect();
+        assert_eq!(counts, vec![1, 1, 2, 2]);
+        assert_eq!(layout.parity_count(0), 0);
+        assert_eq!(layout.parity_count(4), 2);
+        assert_eq!(layout.parity_count(5), 3);
+        assert_eq!(layout.parity_count(11), 6);
+        assert_eq!(layout.stripe_count(11), 3);
+    }
+
+    #[test]
+    fn rebuilds_missing_data_and_parity() {
+        let layout = ErasureLayout::DEFAULT;
+        let chunks = stripe(4);
+        let parity = encode(layout, &chunks);
+        assert_eq!(parity.len(), 2);
+
+        // One data chunk and one parity shard lost
+        let mut data: Vec<Option<Vec<u8>>> = chunks.iter().cloned().map(Some).collect();
+        data[2] = None;
+        let fetched_parity = vec![None, Some(parity[1].clone())];
+        assert_eq!(layout.reconstruct(data, &lengths(&chunks), fetched_parity).unwrap(), chunks);
+
+        // Two data chunks lost, both parity shards there
+        let mut data: Vec<Option<Vec<u8>>> = chunks.iter().cloned().map(Some).collect();
+        data[0] = None;
+        data[3] = None;
+        let fetched_parity = parity.iter().cloned().map(Some).collect();
+        assert_eq!(layout.reconstruct(data, &lengths(&chunks), fetched_parity).unwrap(), chunks);
+    }
+
+    #[test]
+    fn rebuilds_a_short_last_stripe() {
+        let layout = ErasureLayout::DEFAULT;
+        for count in 1..layout.data_shards {
+            let chunks = stripe(count);
+            let parity = encode(layout, &chunks);
+            assert_eq!(parity.len(), layout.parity_shards_for(count));
+
+            // As many chunks lost as the stripe has parity shards
+            let mut data: Vec<Option<Vec<u8>>> = chunks.iter().cloned().map(Some).collect();
+            for chunk in data.iter_mut().rev().take(parity.len()) {
+                *chunk = None;
+            }
+            let fetched_parity = parity.iter().cloned().map(Some).collect();
+            assert_eq!(layout.reconstruct(data, &lengths(&chunks), fetched_parity).unwrap(), chunks, "{} chunks", count);
+        }
+    }
+
+    #[test]
+    fn fails_with_too_few_shards() {
+        let layout = ErasureLayout::DEFAULT;
+        let chunks = stripe(4);
+        let parity = encode(layout, &chunks);
+
+        let mut data: Vec<Option<Vec<u8>>> = chunks.iter().cloned().map(Some).collect();
+        data[0] = None;
+        data[1] = None;
+        let error = layout.reconstruct(data, &lengths(&chunks), vec![Some(parity[0].clone()), None]).unwrap_err();
+        assert!(error.contains("Only 3 of the 4 shards"), "{}", error);
+
+        let short = stripe(1);
+        let short_parity = encode(layout, &short);
+        assert!(layout.reconstruct(vec![None], &lengths(&short), vec![None]).is_err());
+        // The parity list has to match what the stripe keeps
+        assert!(layout.reconstruct(vec![None], &lengths(&short), vec![Some(short_parity[0].clone()), None]).is_err());
+    }
+
+    #[test]
+    fn rejects_stripes_that_dont_fit_the_layout() {
+        let layout = ErasureLayout::DEFAULT;
+        assert!(layout.parity(&[]).is_err());
+        let too_many = stripe(5);
+        let refs: Vec<&[u8]> = too_many.iter().map(|chunk| chunk.as_slice()).collect();
+        assert!(layout.parity(&refs).is_err());
+
+        let chunks = stripe(2);
+        let mut parity = encode(layout, &chunks);
+        parity[0].push(0);
+        let data = vec![None, Some(chunks[1].clone())];
+        assert_eq!(layout.reconstruct(data, &lengths(&chunks), vec![Some(parity[0].clone())]).unwrap_err(), "Parity shard has the wrong size");
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{self, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::checksum::{crc32, crc32_parts};
+
+// Every message between nodes travels as one frame (integers big-endian):
+//
+//   magic     4 bytes   "DSWP"
+//   version   1 byte    FRAME_VERSION
+//   type      1 byte    message type
+//   length    4 bytes   payload length
+//   payload   length bytes
+//   checksum  4 bytes   CRC-32 of everything before it
+//
+// Readers loop until the whole frame has arrived, so a message split over several TCP
+// segments is never cut short. The payload buffer grows as its bytes arrive rather than
+// being allocated from the announced length, so a header alone can't make a node set
+// aside MAX_PAYLOAD_LEN. The async versions do the same on tokio streams.
+pub const FRAME_MAGIC: &[u8; 4] = b"DSWP";
+pub const FRAME_VERSION: u8 = 1;
+const HEADER_LEN: usize = 4 + 1 + 1 + 4;
+
+// Room for the largest content-defined chunk once encoded and encrypted, plus headers
+pub const MAX_PAYLOAD_LEN: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub message_type: u8,
+    pub payload: Vec<u8>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Frame {
+    pub fn new(message_type: u8, payload: impl Into<Vec<u8>>) -> Frame {
+        Frame { message_type, payload: payload.into() }
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        if self.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(invalid_data(format!("Payload of {} bytes is over the {} byte frame limit", self.payload.len(), MAX_PAYLOAD_LEN)));
+        }
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len() + 4);
+        bytes.extend_from_slice(FRAME_MAGIC);
+        bytes.push(FRAME_VERSION);
+        bytes.push(self.message_type);
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        Ok(bytes)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes()?)?;
+        writer.flush()
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Frame> {
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let (message_type, length) = parse_header(&header)?;
+
+        let mut payload = Vec::new();
+        reader.take(length as u64).read_to_end(&mut payload)?;
+        check_payload_len(&payload, length)?;
+        let mut checksum = [0u8; 4];
+        reader.read_exact(&mut checksum)?;
+        check_frame(&header, payload, checksum, message_type)
+    }
+
+    pub async fn write_to_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes()?).await?;
+        writer.flush().await
+    }
+
+    pub async fn read_from_async<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header).await?;
+        let (message_type, length) = parse_header(&header)?;
+
+        let mut payload = Vec::new();
+        reader.take(length as u64).read_to_end(&mut payload).await?;
+        check_payload_len(&payload, length)?;
+        let mut checksum = [0u8; 4];
+        reader.read_exact(&mut checksum).await?;
+        check_frame(&header, payload, checksum, message_type)
+    }
+}
+
+// Message type and payload length of a frame header
+fn parse_header(header: &[u8; HEADER_LEN]) -> io::Result<(u8, usize)> {
+    if &header[..4] != FRAME_MAGIC {
+        return Err(invalid_data("Not a dStorage frame".into()));
+    }
+    if header[4] != FRAME_VERSION {
+        return Err(invalid_data(format!("Unsupported frame version {}", header[4])));
+    }
+    let length = u32::from_be_bytes(header[6..10].try_into().unwrap()) as usize;
+    if length > MAX_PAYLOAD_LEN {
+        return Err(invalid_data(format!("Frame announces {} bytes, over the {} byte limit", length, MAX_PAYLOAD_LEN)));
+    }
+    Ok((header[5], length))
+}
+
+fn check_payload_len(payload: &[u8], length: usize) -> io::Result<()> {
+    if payload.len() != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Frame ends after {} of its {} payload bytes", payload.len(), length)));
+    }
+    Ok(())
+}
+
+fn check_frame(header: &[u8; HEADER_LEN], payload: Vec<u8>, checksum: [u8; 4], message_type: u8) -> io::Result<Frame> {
+    if crc32_parts(&[header, &payload]) != u32::from_be_bytes(checksum) {
+        return Err(invalid_data("Frame checksum mismatch".into()));
+    }
+    Ok(Frame { message_type, payload })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_bytes() -> Vec<u8> {
+        Frame::new(7, b"chunk payload".to_vec()).to_bytes().unwrap()
+    }
+
+    // Every frame is read by both readers, which must agree up to std and tokio wording
+    // their own end-of-stream errors differently
+    fn read(bytes: &[u8]) -> io::Result<Frame> {
+        let sync = Frame::read_from(&mut &bytes[..]);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let async_ = runtime.block_on(Frame::read_from_async(&mut &bytes[..]));
+        match (&sync, &async_) {
+            (Ok(a), Ok(b)) => assert_eq!(a, b),
+            (Err(a), Err(b)) => assert_eq!(a.kind(), b.kind()),
+            _ => panic!("Readers disagree: {:?} and {:?}", sync, async_),
+        }
+        sync
+    }
+
+    fn read_error(bytes: &[u8]) -> io::Error {
+        read(bytes).unwrap_err()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let bytes = frame_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + b"chunk payload".len() + 4);
+        assert_eq!(read(&bytes).unwrap(), Frame::new(7, b"chunk payload".to_vec()));
+        assert_eq!(read(&Frame::new(1, Vec::new()).to_bytes().unwrap()).unwrap(), Frame::new(1, Vec::new()));
+    }
+
+    #[test]
+    fn rejects_damaged_headers() {
+        let mut bytes = frame_bytes();
+        bytes[0] = b'X';
+        let error = read_error(&bytes);
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Not a dStorage frame"));
+
+        let mut bytes = frame_bytes();
+        bytes[4] = FRAME_VERSION + 1;
+        assert!(read_error(&bytes).to_string().contains("Unsupported frame version"));
+
+        let mut bytes = frame_bytes();
+        bytes[6..10].copy_from_slice(&(MAX_PAYLOAD_LEN as u32 + 1).to_be_bytes());
+        let error = read_error(&bytes);
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("over the"));
+    }
+
+    #[test]
+    fn rejects_checksum_mismatches() {
+        for position in [5, HEADER_LEN, HEADER_LEN + 5] {
+            let mut bytes = frame_bytes();
+            bytes[position] ^= 0x01;
+            let error = read_error(&bytes);
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "byte {} flipped", position);
+            assert!(error.to_string().contains("checksum mismatch"));
+        }
+        let mut bytes = frame_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        assert!(read_error(&bytes).to_string().contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let bytes = frame_bytes();
+        for len in [HEADER_LEN - 1, HEADER_LEN + 3, bytes.len() - 1] {
+            assert_eq!(read_error(&bytes[..len]).kind(), io::ErrorKind::UnexpectedEof, "cut at {}", len);
+        }
+        assert!(read_error(&bytes[..HEADER_LEN + 3]).to_string().contains("after 3 of its 13"));
+
+        // A header announcing the largest payload followed by a short body fails on the
+        // missing bytes rather than waiting on or allocating the announced length
+        let mut bytes = frame_bytes()[..HEADER_LEN].to_vec();
+        bytes[6..10].copy_from_slice(&(MAX_PAYLOAD_LEN as u32).to_be_bytes());
+        bytes.extend_from_slice(&[0u8; 10]);
+        let error = read_error(&bytes);
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(error.to_string().contains(&format!("after 10 of its {}", MAX_PAYLOAD_LEN)));
+    }
+
+    #[test]
+    fn refuses_to_write_oversized_payloads() {
+        assert!(Frame::new(1, vec![0u8; MAX_PAYLOAD_LEN + 1]).to_bytes().is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::chunk_store::sha256_hex;
+
+// Raised when downloaded data doesn't match the hashes recorded in its manifest at upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityError {
+    Chunk {
+        index: usize,
+        node: String,
+        expected: String,
+        actual: String,
+    },
+    ParityShard {
+        index: usize,
+        node: String,
+        expected: String,
+        actual: String,
+    },
+    File {
+        file_name: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::Chunk { index, node, expected, actual } => write!(
+                f,
+                "Chunk {} served by node {} is corrupt: expected hash {}, got {}",
+                index, node, expected, actual
+            ),
+            IntegrityError::ParityShard { index, node, expected, actual } => write!(
+                f,
+                "Parity shard {} served by node {} is corrupt: expected hash {}, got {}",
+                index, node, expected, actual
+            ),
+            IntegrityError::File { file_name, expected, actual } => write!(
+                f,
+                "File '{}' is corrupt after reassembly: expected hash {}, got {}",
+                file_name, expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for IntegrityError {}
+
+pub fn verify_chunk(index: usize, node: &str, expected: &str, data: &[u8]) -> Result<(), IntegrityError> {
+    let actual = sha256_hex(data);
+    if actual != expected {
+        return Err(IntegrityError::Chunk {
+            index,
+            node: node.to_string(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+pub fn verify_parity_shard(index: usize, node: &str, expected: &str, data: &[u8]) -> Result<(), IntegrityError> {
+    let actual = sha256_hex(data);
+    if actual != expected {
+        return Err(IntegrityError::ParityShard {
+            index,
+            node: node.to_string(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+pub fn verify_file(file_name: &str, expected: &str, data: &[u8]) -> Result<(), IntegrityError> {
+    let actual = sha256_hex(data);
+    if actual != expected {
+        return Err(IntegrityError::File {
+            file_name: file_name.to_string(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
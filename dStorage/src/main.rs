@@ -1,14 +1,50 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::fs;
 use std::io;
-use std::net::{TcpListener, SocketAddr, TcpStream};
+use std::future::Future;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, OnceLock};
 use std::thread;
+use std::time::Duration;
 use std::io::prelude::*;
-use rusqlite::{Connection, Result, params};
+use rusqlite::{Connection, OptionalExtension, Result, params};
+use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::path::Path;
 use std::fs::OpenOptions;
-use std::io::{Write, Read};
+use std::io::Write;
+
+mod cdc;
+mod checksum;
+mod chunk_store;
+mod codec;
+mod decode_table;
+mod dictionary;
+mod download;
+mod encryption;
+mod erasure;
+mod frame;
+mod integrity;
+mod manifest;
+mod message;
+mod replication;
+mod server;
+mod upload_session;
+mod wire;
+
+use cdc::ChunkSizes;
+use chunk_store::ChunkStore;
+use codec::CodecId;
+use decode_table::DecodeTable;
+use dictionary::Dictionary;
+use download::{RangeReply, StoredHashes};
+use encryption::{ChunkCipher, Encryption, KeySource};
+use erasure::ErasureLayout;
+use manifest::{ChunkEntry, Manifest, ShardEntry};
+use message::{ErrorCode, Message};
+use server::{Peer, Server};
+use upload_session::UploadSession;
 
 
 struct FilePointer {
@@ -17,10 +53,13 @@ struct FilePointer {
     file_name: String,
     dictionary_in_place: bool,
     encoded_text_in_place: bool,
+    codec: CodecId,
 }
 
 impl FilePointer {
     fn write_dictionary(&self, dictionary_bytes: Vec<u8>) -> Result<(), io::Error> {
+        Dictionary::parse(&dictionary_bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
         let directory = format!("{}", self.ip);
         let file_name = format!("{}/{}_dictionary.txt", directory, self.file_name);
         let path = Path::new(&file_name);
@@ -57,7 +96,29 @@ impl FilePointer {
 
         file.write_all(&encoded_text_bytes)?;
         println!("Encoded text written to: {}", file_name);
-        Ok(())
+        self.write_stored_hashes()
+    }
+
+    // Keep the hashes of the encoded text in place so corruption is caught before it's served
+    fn write_stored_hashes(&self) -> Result<(), io::Error> {
+        let file_name = format!("{}/{}_encoded_text.txt", self.ip, self.file_name);
+        let hashes = StoredHashes::compute(&mut io::BufReader::new(File::open(&file_name)?))?;
+        let hash_file_name = format!("{}/{}_encoded_text.sha256", self.ip, self.file_name);
+        fs::write(&hash_file_name, hashes.to_text())
+    }
+
+    fn read_dictionary(&self) -> Result<Dictionary, io::Error> {
+        let file_name = format!("{}/{}_dictionary.txt", self.ip, self.file_name);
+        let path = Path::new(&file_name);
+
+        if path.exists() {
+            println!("Reading dictionary from: {}", file_name);
+            let data = fs::read(path)?;
+            Dictionary::parse(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        } else {
+            println!("File doesn't exist: {}", file_name);
+            Err(io::Error::new(io::ErrorKind::NotFound, "File not found"))
+        }
     }
 
     fn read_encoded_text(&self) -> Result<Vec<u8>, io::Error> {
@@ -67,30 +128,62 @@ impl FilePointer {
         if path.exists() {
             println!("Reading encoded text from: {}", file_name);
             let data = fs::read(path)?;
+
+            let expected = self.read_stored_hashes()?.file_hash;
+            let actual = chunk_store::sha256_hex(&data);
+            if expected != actual {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Encoded text of '{}' on node {} is corrupt: expected hash {}, got {}", self.file_name, self.ip, expected, actual),
+                ));
+            }
             Ok(data)
         } else {
             println!("File doesn't exist: {}", file_name);
             Err(io::Error::new(io::ErrorKind::NotFound, "File not found"))
         }
     }
+
+    fn read_stored_hashes(&self) -> Result<StoredHashes, io::Error> {
+        let hash_file_name = format!("{}/{}_encoded_text.sha256", self.ip, self.file_name);
+        StoredHashes::parse(&fs::read_to_string(&hash_file_name)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Part of the encoded text, checked against the hashes stored at upload, with the size
+    // and hash of all of it so the client can check the whole file once reassembled
+    fn read_encoded_range(&self, offset: u64, length: u32) -> Result<RangeReply, io::Error> {
+        let file_name = format!("{}/{}_encoded_text.txt", self.ip, self.file_name);
+        let hashes = self.read_stored_hashes()?;
+        let (file_size, data) = download::read_range(Path::new(&file_name), offset, length, &hashes).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Encoded text of '{}' on node {} is corrupt: {}", self.file_name, self.ip, e),
+            ),
+            _ => e,
+        })?;
+        Ok(RangeReply { file_size, file_hash: hashes.file_hash, data })
+    }
 }
 
 // Define Node struct
 #[derive(Debug, Clone)]
 struct Node {
     frequency: i64,
-    letter: Option<char>,    
+    symbol: Option<u8>,    
     left: Option<Box<Node>>,
     right: Option<Box<Node>>,
 }
 
 struct Compressor {
-    text: String,
+    data: Vec<u8>,
 }
 
 impl Compressor {
-    // Find the smallest item in the list of nodes
-    fn find_smallest_item(&self, nodes: &Vec<Node>) -> (usize, Node) {
+    // Find the smallest item in the list of nodes. Ties go to the earliest node, which
+    // together with the sorted initial order keeps the tree identical between runs.
+    // Only kept as the reference implementation for the tree construction benchmark.
+    #[cfg(test)]
+    fn find_smallest_item(&self, nodes: &[Node]) -> (usize, Node) {
         let mut smallest_index = 0;
         let mut smallest_node = &nodes[0];
 
@@ -105,6 +198,7 @@ impl Compressor {
     }
 
     // Merge the two smallest nodes and return the new node
+    #[cfg(test)]
     fn merge_smallest_branches(&self, mut nodes: Vec<Node>) -> Node {
         while nodes.len() > 1 {
             let (first_index, first_smallest) = self.find_smallest_item(&nodes);
@@ -115,7 +209,7 @@ impl Compressor {
 
             let merged_node = Node {
                 frequency: first_smallest.frequency + second_smallest.frequency,
-                letter: None,
+                symbol: None,
                 left: Some(Box::new(first_smallest)),
                 right: Some(Box::new(second_smallest)),
             };
@@ -126,11 +220,43 @@ impl Compressor {
         nodes.pop().unwrap() // Return the last node, which is the root of the Huffman tree
     }
 
+    // Build the Huffman tree with a min-heap. Nodes are keyed on (frequency, creation order)
+    // so ties break exactly like merge_smallest_branches, and subtrees are moved out of
+    // their slot instead of being cloned.
+    fn build_tree(&self, nodes: Vec<Node>) -> Node {
+        let mut heap = BinaryHeap::with_capacity(nodes.len());
+        let mut slots: Vec<Option<Node>> = Vec::with_capacity(nodes.len() * 2);
+        for node in nodes {
+            heap.push(Reverse((node.frequency, slots.len())));
+            slots.push(Some(node));
+        }
+
+        while heap.len() > 1 {
+            let Reverse((_, first_index)) = heap.pop().unwrap();
+            let Reverse((_, second_index)) = heap.pop().unwrap();
+            let first_smallest = slots[first_index].take().unwrap();
+            let second_smallest = slots[second_index].take().unwrap();
+
+            let merged_node = Node {
+                frequency: first_smallest.frequency + second_smallest.frequency,
+                symbol: None,
+                left: Some(Box::new(first_smallest)),
+                right: Some(Box::new(second_smallest)),
+            };
+
+            heap.push(Reverse((merged_node.frequency, slots.len())));
+            slots.push(Some(merged_node));
+        }
+
+        let Reverse((_, root_index)) = heap.pop().unwrap(); // The last node left is the root of the Huffman tree
+        slots[root_index].take().unwrap()
+    }
+
     // Generate Huffman codes by traversing the tree
-    fn generate_codes(&self, node: &Node, code: String, codes: &mut HashMap<char, String>) {
-        if let Some(letter) = node.letter {
-            // If it's a leaf node, store the character and its code
-            codes.insert(letter, code);
+    fn generate_codes(&self, node: &Node, code: String, codes: &mut HashMap<u8, String>) {
+        if let Some(symbol) = node.symbol {
+            // If it's a leaf node, store the byte and its code
+            codes.insert(symbol, code);
         } else {
             // Recursively traverse the left and right children
             if let Some(ref left) = node.left {
@@ -142,21 +268,22 @@ impl Compressor {
         }
     }
 
-    // Create a frequency table and compress
-    fn compress(&self) -> (String, HashMap<char, String>) {
+    // Create a frequency table and compress into a bit-packed byte stream
+    fn compress(&self) -> (Vec<u8>, HashMap<u8, String>) {
         let mut nodes: Vec<Node> = Vec::new();
-        let mut frequency_map = HashMap::new();
+        // Ordered by byte value so the nodes are always created in the same order
+        let mut frequency_map = BTreeMap::new();
 
-        // Calculate frequencies of characters
-        for character in self.text.chars() {
-            *frequency_map.entry(character).or_insert(0) += 1;
+        // Calculate frequencies of bytes
+        for &byte in &self.data {
+            *frequency_map.entry(byte).or_insert(0) += 1;
         }
 
         // Create nodes from the frequency map
-        for (letter, frequency) in frequency_map {
+        for (symbol, frequency) in frequency_map {
             nodes.push(Node {
                 frequency: frequency,
-                letter: Some(letter),
+                symbol: Some(symbol),
                 left: None,
                 right: None,
             });
@@ -164,166 +291,327 @@ impl Compressor {
 
         //println!("Initial Nodes: {:?}", nodes);
 
+        // An empty input has no tree at all, only the zero symbol count is stored
+        if nodes.is_empty() {
+            return (pack_bits(&[], 0), HashMap::new());
+        }
+
         // Merge branches to create the Huffman tree
-        let huffman_tree = self.merge_smallest_branches(nodes);
+        let huffman_tree = self.build_tree(nodes);
 
-        // Generate Huffman codes by traversing the tree
-        let mut codes = HashMap::new();
-        self.generate_codes(&huffman_tree, String::new(), &mut codes);
+        // Generate Huffman codes by traversing the tree, then replace them with the
+        // canonical codes of the same lengths so the table can be stored as lengths only.
+        // A lone symbol is the root itself and would get the empty code, so give it one bit.
+        let mut tree_codes = HashMap::new();
+        self.generate_codes(&huffman_tree, String::new(), &mut tree_codes);
+        let code_lengths = tree_codes.iter().map(|(&symbol, code)| (symbol, code.len().max(1) as u8)).collect();
+        let codes = dictionary::canonical_codes(&code_lengths);
 
         //println!("Huffman Codes: {:?}", codes);
 
-        // You can now use these codes to encode the text
-        let mut encoded_text = String::new();
-        for character in self.text.chars() {
-            if let Some(code) = codes.get(&character) {
-                encoded_text.push_str(code);
+        // Encode the data as individual bits, then pack them eight to a byte
+        let mut bits: Vec<u8> = Vec::new();
+        for byte in &self.data {
+            if let Some(code) = codes.get(byte) {
+                bits.extend(code.bytes().map(|bit| bit - b'0'));
             }
         }
+        let encoded_text = pack_bits(&bits, self.data.len() as u64);
 
-        //println!("Encoded Text: {}", encoded_text);
-        (encoded_text, codes) // Return the encoded text and the encoding table
+        //println!("Encoded Text: {:?}", encoded_text);
+        (encoded_text, codes) // Return the packed encoded text and the encoding table
     }
 }
 
-// Decoder struct
+// Decoder struct, reads the packed form produced by Compressor::compress
 struct Decoder {
-    encoded_text: String,
-    encoding_table: HashMap<char, String>,
+    encoded_text: Vec<u8>,
+    encoding_table: HashMap<u8, String>,
 }
 
 impl Decoder {
-    fn decode(&self) -> String {
-        let mut binary:String = String::new();
-        let mut decoded_text:String = String::new();
-        let reversed_table: HashMap<String, char> = self.encoding_table
-            .iter()
-            .map(|(k, v)| (v.clone(), *k)) // Reverse the encoding table
-            .collect();
-
-        for bit in self.encoded_text.chars() {
-            binary.push(bit);
-            if let Some(&letter) = reversed_table.get(&binary) {
-                decoded_text.push(letter);
-                binary.clear();
-            }
-        }
+    // Rebuild a decoder from a serialized dictionary, e.g. a stored _dictionary.txt file
+    fn from_dictionary(encoded_text: Vec<u8>, dictionary_bytes: &[u8]) -> Result<Decoder, String> {
+        let dictionary = Dictionary::parse(dictionary_bytes)?;
+        Ok(Decoder {
+            encoded_text,
+            encoding_table: dictionary.encoding_table(),
+        })
+    }
+
+    fn decode(&self) -> Result<Vec<u8>, String> {
+        // Resolve several bits per table lookup instead of growing and hashing a code string per bit
+        let decode_table = DecodeTable::new(&self.encoding_table)?;
+        let decoded_data = decode_table.decode(&self.encoded_text)?;
 
-        //println!("Decoded text: {}", decoded_text);
-        decoded_text
+        //println!("Decoded data: {:?}", decoded_data);
+        Ok(decoded_data)
     }
 }
 
 
+// Default size of a fixed-size chunk, 4 MiB
+const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+// Directory holding the chunks of files uploaded from this machine
+const LOCAL_CHUNK_STORE: &str = "chunks";
+// Directory uploads from peers are staged in until they are committed
+const UPLOAD_STAGING: &str = "uploads";
+// Name of this node in the peer table and in manifests, for the chunks kept in the local
+// chunk store. It's the address of the interface that routes to other hosts, which peers
+// reach it on, found by connecting a UDP socket (that sends nothing). A node with no route
+// anywhere can only serve itself and goes by the loopback address.
+fn local_node() -> &'static str {
+    static LOCAL_NODE: OnceLock<String> = OnceLock::new();
+    LOCAL_NODE.get_or_init(|| {
+        let routed = std::net::UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+            socket.connect("192.0.2.1:9")?;
+            socket.local_addr()
+        });
+        match routed {
+            Ok(addr) if !addr.ip().is_unspecified() => addr.ip().to_string(),
+            _ => "127.0.0.1".to_string(),
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SliceMode {
+    // Split into this many slices of equal length, the last one takes the remainder. Data
+    // shorter than that is split into one byte slices, empty data into none.
+    Count(usize),
+    // Split into slices of this many bytes, the last one may be shorter
+    FixedSize(usize),
+    // Split where the content hash says so, so unchanged data keeps producing the same slices
+    ContentDefined(ChunkSizes),
+}
+
+// A slice of a file together with where it belongs, so it can be stored on its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Chunk {
+    index: usize,
+    offset: u64,
+    data: Vec<u8>,
+}
+
 struct Slicer {
-    text: String,
-    slice_amount: usize, 
+    data: Vec<u8>,
+    mode: SliceMode,
 }
 
 impl Slicer {
-    fn slice(&self) -> Vec<&str> {
-        let text_len = self.text.len();
-        let mut slices = vec![];
-
-        if self.slice_amount == 0 {
-            return slices; 
-        }
-
-        let whole_slices = text_len % self.slice_amount == 0;
-        let slice_len = text_len / self.slice_amount;
-        
-        if whole_slices {
-            for i in 0..self.slice_amount {
-                let start = i * slice_len;
-                let end = start + slice_len;
-                slices.push(&self.text[start..end]);
+    fn slice(&self) -> Vec<Chunk> {
+        let data_len = self.data.len();
+        let mut ranges = vec![];
+
+        match self.mode {
+            SliceMode::Count(0) | SliceMode::FixedSize(0) => {}
+            SliceMode::Count(_) if data_len == 0 => {}
+            SliceMode::Count(slice_amount) => {
+                let slice_amount = slice_amount.min(data_len);
+                let slice_len = data_len / slice_amount;
+                for i in 0..(slice_amount - 1) {
+                    let start = i * slice_len;
+                    ranges.push(start..start + slice_len);
+                }
+                ranges.push((slice_amount - 1) * slice_len..data_len);
             }
-        } else {
-            for i in 0..(self.slice_amount - 1) {
-                let start = i * slice_len;
-                let end = start + slice_len;
-                slices.push(&self.text[start..end]);
+            SliceMode::FixedSize(chunk_size) => {
+                for start in (0..data_len).step_by(chunk_size) {
+                    ranges.push(start..data_len.min(start + chunk_size));
+                }
+            }
+            SliceMode::ContentDefined(sizes) => {
+                ranges = cdc::chunk_ranges(&self.data, &sizes);
             }
-            let start = (self.slice_amount - 1) * slice_len;
-            slices.push(&self.text[start..]);
         }
-        
-        slices
+
+        ranges
+            .into_iter()
+            .enumerate()
+            .map(|(index, range)| Chunk {
+                index,
+                offset: range.start as u64,
+                data: self.data[range].to_vec(),
+            })
+            .collect()
     }
 }
 
-struct Compiler<'a> {
-    slices: Vec<&'a str>,
+struct Compiler {
+    chunks: Vec<Chunk>,
 }
 
-impl<'a> Compiler<'a> {
-    fn compile(&self) -> String {
-        let mut result = String::new();
-        for slice in &self.slices {
-            result.push_str(slice);
+impl Compiler {
+    // Fetch every chunk listed in the manifest and decode it. Chunks can come from any of
+    // the nodes hosting them, fetch_chunk is called with (node, chunk hash) for each node in
+    // turn until one of them returns a chunk matching the hash recorded at upload. When the
+    // file has parity shards, chunks no node could serve are rebuilt from the rest of their
+    // stripe. Encrypted files need the keys they were uploaded with.
+    fn from_manifest<F>(manifest: &Manifest, cipher: Option<&ChunkCipher>, mut fetch_chunk: F) -> Result<Compiler, String>
+    where
+        F: FnMut(&str, &str) -> Result<Vec<u8>, String>,
+    {
+        if manifest.encryption != Encryption::None && cipher.is_none() {
+            return Err(format!("'{}' is encrypted and no key was given", manifest.file_name));
+        }
+
+        let mut encoded_chunks = Vec::with_capacity(manifest.chunks.len());
+        let mut missing = Vec::new();
+        for (index, entry) in manifest.chunks.iter().enumerate() {
+            let fetched = Compiler::fetch_from_nodes(&entry.nodes, &entry.hash, &mut fetch_chunk, |node, data| {
+                integrity::verify_chunk(index, node, &entry.hash, data).map_err(|e| e.to_string())
+            });
+            match fetched {
+                Ok(data) => encoded_chunks.push(Some(data)),
+                Err(errors) => {
+                    eprintln!("Chunk {} is not available from any node [{}]", index, errors);
+                    missing.push(format!("Chunk {} ({}) is not available from any node [{}]", index, entry.hash, errors));
+                    encoded_chunks.push(None);
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            let layout = manifest.erasure.ok_or_else(|| missing.join(", "))?;
+            Compiler::rebuild_missing(manifest, layout, &mut encoded_chunks, &mut fetch_chunk)?;
+        }
+
+        let mut chunks = Vec::with_capacity(manifest.chunks.len());
+        for (index, (entry, encoded_chunk)) in manifest.chunks.iter().zip(encoded_chunks).enumerate() {
+            let encoded_chunk = encoded_chunk.ok_or_else(|| format!("Chunk {} could not be rebuilt", index))?;
+            let encoded_chunk = match cipher {
+                Some(cipher) => cipher.decrypt_chunk(&entry.hash, entry.offset, &encoded_chunk)?,
+                None => encoded_chunk,
+            };
+            let data = entry.codec.codec().decode(&encoded_chunk)?;
+            if data.len() as u64 != entry.size {
+                return Err(format!("Chunk {} decoded to {} bytes instead of {}", index, data.len(), entry.size));
+            }
+            chunks.push(Chunk { index, offset: entry.offset, data });
+        }
+        Ok(Compiler { chunks })
+    }
+
+    // Ask each node in turn until one returns data that passes verify. On failure, returns
+    // what every node answered.
+    fn fetch_from_nodes<F, V>(nodes: &[String], hash: &str, fetch_chunk: &mut F, verify: V) -> Result<Vec<u8>, String>
+    where
+        F: FnMut(&str, &str) -> Result<Vec<u8>, String>,
+        V: Fn(&str, &[u8]) -> Result<(), String>,
+    {
+        let mut errors = Vec::new();
+        for node in nodes {
+            match fetch_chunk(node, hash).and_then(|data| verify(node, &data).map(|_| data)) {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    eprintln!("{} from node {} rejected: {}", hash, node, e);
+                    errors.push(format!("{}: {}", node, e));
+                }
+            }
+        }
+        Err(errors.join(", "))
+    }
+
+    // Fill in the missing encoded chunks from the other chunks and the parity shards of
+    // their stripes
+    fn rebuild_missing<F>(manifest: &Manifest, layout: ErasureLayout, encoded_chunks: &mut [Option<Vec<u8>>], fetch_chunk: &mut F) -> Result<(), String>
+    where
+        F: FnMut(&str, &str) -> Result<Vec<u8>, String>,
+    {
+        if manifest.parity.len() != layout.parity_count(manifest.chunks.len()) {
+            return Err(format!("Manifest of {} lists {} parity shards, which doesn't match its layout", manifest.file_name, manifest.parity.len()));
+        }
+
+        for (stripe, stripe_chunks) in encoded_chunks.chunks_mut(layout.data_shards).enumerate() {
+            if stripe_chunks.iter().all(|chunk| chunk.is_some()) {
+                continue;
+            }
+            let first_chunk = stripe * layout.data_shards;
+            let entries = &manifest.chunks[first_chunk..first_chunk + stripe_chunks.len()];
+            let first_shard = stripe * layout.parity_shards;
+            let shard_entries = &manifest.parity[first_shard..first_shard + layout.parity_shards_for(stripe_chunks.len())];
+
+            let mut parity = Vec::with_capacity(shard_entries.len());
+            for (j, shard) in shard_entries.iter().enumerate() {
+                let shard_index = first_shard + j;
+                let fetched = Compiler::fetch_from_nodes(&shard.nodes, &shard.hash, fetch_chunk, |node, data| {
+                    integrity::verify_parity_shard(shard_index, node, &shard.hash, data).map_err(|e| e.to_string())
+                });
+                parity.push(fetched.ok());
+            }
+
+            let data_lengths: Vec<usize> = entries.iter().map(|entry| entry.stored_size as usize).collect();
+            let rebuilt = layout
+                .reconstruct(stripe_chunks.to_vec(), &data_lengths, parity)
+                .map_err(|e| format!("Stripe {} of {}: {}", stripe, manifest.file_name, e))?;
+            for (i, (slot, data)) in stripe_chunks.iter_mut().zip(rebuilt).enumerate() {
+                if slot.is_none() {
+                    let index = first_chunk + i;
+                    integrity::verify_chunk(index, "parity", &entries[i].hash, &data).map_err(|e| e.to_string())?;
+                    println!("Chunk {} rebuilt from parity", index);
+                    *slot = Some(data);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Put the chunks back in index order and check that they cover the file without gaps
+    fn compile(&self) -> Result<Vec<u8>, String> {
+        let mut chunks: Vec<&Chunk> = self.chunks.iter().collect();
+        chunks.sort_by_key(|chunk| chunk.index);
+
+        let mut result = Vec::new();
+        for (expected_index, chunk) in chunks.into_iter().enumerate() {
+            if chunk.index != expected_index {
+                return Err(format!("Missing chunk {}", expected_index));
+            }
+            if chunk.offset != result.len() as u64 {
+                return Err(format!("Chunk {} starts at offset {} but {} bytes come before it", chunk.index, chunk.offset, result.len()));
+            }
+            result.extend_from_slice(&chunk.data);
+        }
+        Ok(result)
+    }
+
+    // Compile and check the result against the size and hash recorded in the manifest
+    fn compile_verified(&self, manifest: &Manifest) -> Result<Vec<u8>, String> {
+        let result = self.compile()?;
+        if result.len() as u64 != manifest.size {
+            return Err(format!("File '{}' is {} bytes after reassembly instead of {}", manifest.file_name, result.len(), manifest.size));
         }
-        result
+        integrity::verify_file(&manifest.file_name, &manifest.hash, &result).map_err(|e| e.to_string())?;
+        Ok(result)
     }
 }
 
 struct Receiver {
     primary_port: i32,
     secondary_port: i32,
-    response: Option<fn(TcpStream)>,
+    response: Option<fn(&Peer, Message) -> Message>,
 }
 
 impl Receiver {
-    fn receive(&self) {
+    async fn receive<S: Future<Output = ()>>(&self, shutdown: S) -> io::Result<()> {
         let addrs = [
             SocketAddr::from(([127, 0, 0, 1], self.primary_port as u16)),
             SocketAddr::from(([127, 0, 0, 1], self.secondary_port as u16)),
         ];
 
         // Try binding the listener to both ports
-        let listener = TcpListener::bind(&addrs[..]).expect("Failed to bind to address");
+        let response = self.response.unwrap_or(handle_client);
+        let server = Server::bind(&addrs[..], server::DEFAULT_MAX_CONNECTIONS, Arc::new(response)).await?;
 
         println!("Server listening on ports {} and {}", self.primary_port, self.secondary_port);
 
-        // Accept incoming connections
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let response = self.response;
-
-                    thread::spawn(move || {
-                        match response {
-                            Some(func) => {
-                                func(stream);
-                            }
-                            None => {
-                                handle_client(stream, None);
-                            }
-                        }
-                    });
-                }
-                Err(e) => {
-                    eprintln!("Failed to accept connection: {}", e);
-                }
-            }
-        }
+        // Accept incoming connections until shutdown
+        server.run(shutdown).await
     }
 }
 
-fn handle_client(mut stream: TcpStream, response: Option<String>) {
-    let mut buffer = [0; 1024];
-    let bytes_read = stream.read(&mut buffer).expect("Failed to read from client!");
-
-    let request = String::from_utf8_lossy(&buffer[..bytes_read]);
-    println!("Received request: {}", request);
-
-    match response {
-        Some(response) => {
-            stream.write(response.as_bytes()).expect("Failed to write response.");
-        }
-        None => {
-            println!("No response to write.");
-        }
-    }
+fn handle_client(_peer: &Peer, request: Message) -> Message {
+    println!("Received request: {:?}", request);
+    Message::ack()
 }
 fn bits_to_u8(bits: &[u8]) -> Result<u8, String> {
     if bits.len() != 8 {
@@ -340,265 +628,757 @@ fn bits_to_u8(bits: &[u8]) -> Result<u8, String> {
     Ok(result)
 }
 
+// Size of the header written by pack_bits
+const PACKED_HEADER_LEN: usize = 1 + 8;
+
+// Pack individual bits into bytes. The output starts with a header holding how many
+// padding bits were added to the end of the last byte, followed by the number of
+// encoded symbols (u64, big-endian) so the decoder knows exactly where the data ends.
+fn pack_bits(bits: &[u8], symbol_count: u64) -> Vec<u8> {
+    let padding = (8 - bits.len() % 8) % 8;
+    let mut packed = Vec::with_capacity(PACKED_HEADER_LEN + bits.len().div_ceil(8));
+    packed.push(padding as u8);
+    packed.extend_from_slice(&symbol_count.to_be_bytes());
+
+    for chunk in bits.chunks(8) {
+        let mut byte_bits = [0u8; 8];
+        byte_bits[..chunk.len()].copy_from_slice(chunk);
+        packed.push(bits_to_u8(&byte_bits).expect("Huffman codes only contain 0 and 1"));
+    }
+    packed
+}
 
-fn handle_file_upload_request(mut stream: TcpStream, file_name: String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buffer = [0; 1024];
-    let bytes_read = stream.read(&mut buffer)?;
-
-    let conn = Connection::open("pointers.db")?;
 
-    let table_name = "file_pointers";
-    let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type='table' AND name=?1")?;
-    let table_exists = stmt.exists([table_name])?;
+fn create_pointer_table(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_pointers (
+            id INTEGER PRIMARY KEY,
+            ip TEXT NOT NULL,
+            fileName TEXT NOT NULL,
+            dictionaryInPlace INTEGER NOT NULL,
+            encodedTextInPlace INTEGER NOT NULL,
+            codec INTEGER NOT NULL DEFAULT 1
+        )",
+        [],
+    )?;
+
+    // Tables created before codecs existed only hold Huffman-encoded text
+    let columns = pointer_columns(conn)?;
+    if !columns.iter().any(|(name, _)| name == "codec") {
+        conn.execute("ALTER TABLE file_pointers ADD COLUMN codec INTEGER NOT NULL DEFAULT 1", [])?;
+    }
 
-    if !table_exists {
-        conn.execute(
-            "CREATE TABLE file_pointers (
+    // Tables created before the flags became integers hold them as 'TRUE'/'FALSE' text,
+    // which doesn't read back as a bool. SQLite can't change a column's type in place, so
+    // the table is copied into one with integer flags.
+    if columns.iter().any(|(name, kind)| name == "dictionaryInPlace" && kind.eq_ignore_ascii_case("TEXT")) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(
+            "CREATE TABLE file_pointers_migrated (
                 id INTEGER PRIMARY KEY,
                 ip TEXT NOT NULL,
                 fileName TEXT NOT NULL,
-                dictionaryInPlace TEXT NOT NULL,
-                encodedTextInPlace TEXT NOT NULL
-            )",
-            [],
+                dictionaryInPlace INTEGER NOT NULL,
+                encodedTextInPlace INTEGER NOT NULL,
+                codec INTEGER NOT NULL DEFAULT 1
+            );
+            INSERT INTO file_pointers_migrated (id, ip, fileName, dictionaryInPlace, encodedTextInPlace, codec)
+                SELECT id, ip, fileName, dictionaryInPlace IN ('TRUE', '1'), encodedTextInPlace IN ('TRUE', '1'), codec
+                FROM file_pointers;
+            DROP TABLE file_pointers;
+            ALTER TABLE file_pointers_migrated RENAME TO file_pointers;",
         )?;
+        tx.commit()?;
     }
+    Ok(())
+}
 
-    let local_addr = stream.local_addr().unwrap();
-    let ip = local_addr.ip().to_string();
-
-    if buffer[0] == 0b1 {
-        println!("Request with dictionary");
-        let dictionary_buffer = &buffer[1..bytes_read];
+// Name and declared type of every column of file_pointers
+fn pointer_columns(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("PRAGMA table_info(file_pointers)")?;
+    let columns = stmt.query_map([], |row| Ok((row.get(1)?, row.get(2)?)))?.collect();
+    columns
+}
 
-        let mut stmt = conn.prepare("SELECT id, ip, fileName, dictionaryInPlace, encodedTextInPlace FROM file_pointers WHERE dictionaryInPlace='FALSE' AND ip=?1")?;
-        let result = stmt.query_map([ip], |row| {
-            Ok(FilePointer {
-                id: row.get(0)?,
-                ip: row.get(1)?,
-                file_name: row.get(2)?,
-                dictionary_in_place: row.get(3)?,
-                encoded_text_in_place: row.get(4)?,
-            })
-        })?;
+// Commit the upload session of the peer: check every chunk arrived, move the staged data
+// into place as the encoded text of file_name on this node and point to it from
+// file_pointers. The text is encoded with the codec the uploader announced, which carries
+// whatever dictionary it needs, so no separate dictionary is kept.
+fn handle_file_upload_request(conn: &Connection, peer: &Peer, session: &UploadSession) -> Result<Message, Box<dyn std::error::Error>> {
+    create_pointer_table(conn)?;
+    let ip = peer.local_ip.clone();
+
+    let existing: Option<i32> = conn
+        .query_row(
+            "SELECT id FROM file_pointers WHERE ip=?1 AND fileName=?2",
+            params![ip, session.file_name],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let file_pointer = FilePointer {
+        id: existing.unwrap_or(0),
+        ip: ip.clone(),
+        file_name: session.file_name.clone(),
+        dictionary_in_place: false,
+        encoded_text_in_place: true,
+        codec: session.codec,
+    };
 
-        for file_pointer in result {
-            let mut file_pointer = file_pointer?;
-            file_pointer.write_dictionary(dictionary_buffer.to_vec())?;
+    fs::create_dir_all(&ip)?;
+    let encoded_text_path = format!("{}/{}_encoded_text.txt", ip, session.file_name);
+    if let Err(e) = session.assemble(conn, Path::new(&encoded_text_path)) {
+        return Ok(Message::error(ErrorCode::NotReady, e.to_string()));
+    }
+    println!("Encoded text written to: {}", encoded_text_path);
+    file_pointer.write_stored_hashes()?;
+
+    match existing {
+        Some(id) => {
+            conn.execute(
+                "UPDATE file_pointers SET dictionaryInPlace = 0, encodedTextInPlace = 1, codec = ?2 WHERE id = ?1",
+                params![id, session.codec],
+            )?;
         }
-
-    } else if buffer[0] == 0b0 {
-        println!("Request with encoded data");
-        let encoded_text_buffer = &buffer[1..bytes_read];
-
-        let mut stmt = conn.prepare("SELECT id, ip, fileName, dictionaryInPlace, encodedTextInPlace FROM file_pointers WHERE encodedTextInPlace='FALSE' AND ip=?1")?;
-        let result = stmt.query_map([ip], |row| {
-            Ok(FilePointer {
-                id: row.get(0)?,
-                ip: row.get(1)?,
-                file_name: row.get(2)?,
-                dictionary_in_place: row.get(3)?,
-                encoded_text_in_place: row.get(4)?,
-            })
-        })?;
-
-        for file_pointer in result {
-            let mut file_pointer = file_pointer?;
-            file_pointer.write_encoded_text(encoded_text_buffer.to_vec())?;
+        None => {
+            conn.execute(
+                "INSERT INTO file_pointers (ip, fileName, dictionaryInPlace, encodedTextInPlace, codec) VALUES (?1, ?2, 0, 1, ?3)",
+                params![ip, session.file_name, session.codec],
+            )?;
         }
     }
-
-    Ok(())
+    session.discard(conn)?;
+    Ok(Message::ack())
 }
 
-fn handle_file_download(mut stream: TcpStream, file_name: String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buffer = [0; 1024];
-    let bytes_read = stream.read(&mut buffer)?;
-
-    let conn = Connection::open("pointers.db")?;
+// The download request itself was read by the server, which passes on the file name and
+// the range asked for
+fn handle_file_download(conn: &Connection, peer: &Peer, file_name: String, offset: u64, length: u32) -> Result<Message, Box<dyn std::error::Error>> {
     let table_name = "file_pointers";
     
     let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type='table' AND name=?1")?;
     let table_exists = stmt.exists([table_name])?;
 
-    let local_addr = stream.local_addr()?;
-    let ip = local_addr.ip().to_string();
+    let ip = &peer.local_ip;
     if !table_exists {
         let response = format!("Declined: never had file '{}' uploaded to the machine from IP {}", file_name, ip);
-        stream.write_all(response.as_bytes())?;
-        return Ok(());
-    } else {
-        let mut stmt = conn.prepare(
-            "SELECT id, ip, fileName, dictionaryInPlace, encodedTextInPlace FROM file_pointers WHERE fileName=?1 AND ip=?2"
-        )?;
-        
-        let mut rows = stmt.query_map(params![file_name, ip], |row| {
-            Ok(FilePointer {
-                id: row.get(0)?,
-                ip: row.get(1)?,
-                file_name: row.get(2)?,
-                dictionary_in_place: row.get(3)?,
-                encoded_text_in_place: row.get(4)?,
-            })
-        })?;
+        return Ok(Message::error(ErrorCode::NotFound, response));
+    }
+    create_pointer_table(conn)?;
 
-        if let Some(file_pointer) = rows.next() {
-            let file_pointer = file_pointer?;             
-            if file_pointer.encoded_text_in_place {
-                let encoded_data = file_pointer.read_encoded_text()?;
-                stream.write_all(&encoded_data)?;
-                println!("Sent encoded data for file: {}", file_pointer.file_name);
-            } else {
-                let response = "Encoded text not available yet";
-                stream.write_all(response.as_bytes())?;
+    let mut stmt = conn.prepare(
+        "SELECT id, ip, fileName, dictionaryInPlace, encodedTextInPlace, codec FROM file_pointers WHERE fileName=?1 AND ip=?2"
+    )?;
+    
+    let mut rows = stmt.query_map(params![file_name, ip], |row| {
+        Ok(FilePointer {
+            id: row.get(0)?,
+            ip: row.get(1)?,
+            file_name: row.get(2)?,
+            dictionary_in_place: row.get(3)?,
+            encoded_text_in_place: row.get(4)?,
+            codec: row.get(5)?,
+        })
+    })?;
+
+    let reply = if let Some(file_pointer) = rows.next() {
+        let file_pointer = file_pointer?;             
+        if file_pointer.encoded_text_in_place {
+            match file_pointer.read_encoded_range(offset, length) {
+                Ok(range) => {
+                    println!("Sending bytes {}..{} of encoded data for file: {}", offset, offset + range.data.len() as u64, file_pointer.file_name);
+                    Message::Ack { data: range.to_bytes() }
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => Message::error(ErrorCode::BadRequest, e.to_string()),
+                Err(e) => return Err(e.into()),
             }
         } else {
-            let response = format!("Declined: No file '{}' found for IP {}", file_name, ip);
-            stream.write_all(response.as_bytes())?;
+            Message::error(ErrorCode::NotReady, "Encoded text not available yet")
         }
-    }
-
-    Ok(())
+    } else {
+        let response = format!("Declined: No file '{}' found for IP {}", file_name, ip);
+        Message::error(ErrorCode::NotFound, response)
+    };
+    Ok(reply)
 }
 // Function to send a decline response
-fn send_decline_response(addr: std::net::SocketAddr) {
+async fn send_decline_response(addr: std::net::SocketAddr) -> io::Result<()> {
     let send_request = Request {
         ip: addr,
-        message: "Declined: not part of network".to_string(),
+        message: Message::error(ErrorCode::NotInNetwork, "Declined: not part of network"),
     };
-    send_request.sendRequest();
+    send_request.send_request().await
 }
 
-fn handle_requests(mut stream: TcpStream, network_id: String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buffer = [0; 1024];
-    let bytes_read = stream.read(&mut buffer)?;
+// Answer a request a peer of network_id sent. Failures are reported to the peer rather
+// than closing the connection.
+fn handle_requests(network_id: &str, peer: &Peer, request: Message) -> Message {
+    let reply = Connection::open("pointers.db")
+        .map_err(|e| e.into())
+        .and_then(|conn| {
+            upload_session::create_tables(&conn, network_id)?;
+            handle_request(&conn, network_id, peer, request)
+        });
+    reply.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        Message::error(ErrorCode::Internal, e.to_string())
+    })
+}
 
-    let current_stage_code = u32::from_be_bytes(buffer[..4].try_into()?);
-    let conn = Connection::open("pointers.db")?;
-    let table_name = format!("connections{}", network_id);
+fn handle_request(conn: &Connection, network_id: &str, peer: &Peer, request: Message) -> Result<Message, Box<dyn std::error::Error>> {
+    let ip = peer.ip.as_str();
+    let reply = match request {
+        Message::Join => {
+            println!("Join request");
+            replication::create_tables(conn)?;
+            replication::add_peer(conn, ip)?;
+            Message::ack()
+        }
+        Message::Leave => {
+            println!("Leave request");
+            replication::create_tables(conn)?;
+            replication::remove_peer(conn, ip)?;
+            Message::ack()
+        }
+        Message::UploadInit { file_name, size, chunk_count, codec } => {
+            println!("Upload request for '{}' ({} bytes in {} chunks, encoded with {})", file_name, size, chunk_count, codec);
+            UploadSession::start(conn, Path::new(UPLOAD_STAGING), network_id, ip, &file_name, size, chunk_count, codec)
+                .and_then(|session| Ok(session.received_offsets(conn)?))
+                .map(|offsets| Message::Ack { data: upload_session::encode_offsets(&offsets) })
+                .unwrap_or_else(|e| Message::error(ErrorCode::BadRequest, e.to_string()))
+        }
+        Message::UploadChunk { file_name, offset, data } => match UploadSession::load(conn, Path::new(UPLOAD_STAGING), network_id, ip)? {
+            Some(session) if session.file_name == file_name => match session.write_chunk(conn, offset, &data) {
+                Ok(()) => Message::ack(),
+                Err(e) => Message::error(ErrorCode::BadRequest, e.to_string()),
+            },
+            _ => Message::error(ErrorCode::BadRequest, format!("No upload of '{}' was started", file_name)),
+        },
+        Message::UploadCommit { file_name } => match UploadSession::load(conn, Path::new(UPLOAD_STAGING), network_id, ip)? {
+            Some(session) if session.file_name == file_name => {
+                println!("Finishing upload..");
+                handle_file_upload_request(conn, peer, &session)?
+            }
+            _ => Message::error(ErrorCode::BadRequest, format!("No upload of '{}' was started", file_name)),
+        },
+        Message::Download { file_name, offset, length } => {
+            println!("Download of {} bytes at offset {} of '{}'", length, offset, file_name);
+            handle_file_download(conn, peer, file_name, offset, length)?
+        }
+        Message::StoreChunk { file_name, data } => {
+            ChunkStore::create_tables(conn)?;
+            let hash = ChunkStore::new(LOCAL_CHUNK_STORE).hold_replica(conn, ip, &file_name, &data)?;
+            println!("Holding {} of '{}' for {}", hash, file_name, ip);
+            Message::Ack { data: hash.into_bytes() }
+        }
+        Message::FetchChunk { hash } => match ChunkStore::new(LOCAL_CHUNK_STORE).get(&hash) {
+            Ok(data) => Message::Ack { data },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Message::error(ErrorCode::NotFound, format!("No chunk {} on node {}", hash, peer.local_ip)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => Message::error(ErrorCode::BadRequest, e.to_string()),
+            Err(e) => Message::error(ErrorCode::Internal, format!("{} on node {}", e, peer.local_ip)),
+        },
+        Message::ReleaseChunk { file_name, hash } => {
+            ChunkStore::create_tables(conn)?;
+            ChunkStore::new(LOCAL_CHUNK_STORE).release_replica(conn, ip, &file_name, &hash)?;
+            Message::ack()
+        }
+        Message::Ack { .. } | Message::Error { .. } => {
+            println!("Unknown request");
+            Message::error(ErrorCode::BadRequest, "Expected a request")
+        }
+    };
+    Ok(reply)
+}
 
-    let table_exists = conn.prepare(&format!(
-        "SELECT name FROM sqlite_master WHERE type='table' AND name='{}'", table_name
-    ))?.exists([])?;
+async fn listen_for_requests<S: Future<Output = ()>>(shutdown: S) -> io::Result<()> {
+    let network_id = "some_id".to_string(); // Example, use real network ID
+    let handler: server::Handler = Arc::new(move |peer: &Peer, request: Message| handle_requests(&network_id, peer, request));
+    let server = Server::bind(("0.0.0.0", NODE_PORT), server::DEFAULT_MAX_CONNECTIONS, handler).await?;
+    server.run(shutdown).await
+}
 
-    if !table_exists {
-        conn.execute(
-            &format!("CREATE TABLE {} (ip TEXT NOT NULL, stage TEXT NOT NULL)", table_name),
-            [],
-        )?;
+struct Request {
+    ip: SocketAddr,
+    message: Message,
+}
+
+impl Request {
+    async fn send_request(&self) -> io::Result<()> {
+        let mut stream = tokio::net::TcpStream::connect(self.ip).await?;
+        self.message.write_to_async(&mut stream).await
     }
+}
 
-    let ip = stream.peer_addr()?.ip().to_string();
-    let user_exists = conn.prepare(&format!(
-        "SELECT ip FROM {} WHERE ip = ?1", table_name
-    ))?.exists(params![ip])?;
+// Largest piece of a file sent in one UploadChunk message
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
 
-    if user_exists {
-        let mut stmt = conn.prepare(&format!("SELECT stage FROM {} WHERE ip = ?1", table_name))?;
-        let stage: String = stmt.query_row(params![ip], |row| row.get(0))?;
-        match stage.as_str() {
-            "0011" => {
-                println!("Upload request stage");
+fn exchange(stream: &mut TcpStream, message: &Message) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    message.write_to(stream)?;
+    match Message::read_from(stream)? {
+        Message::Ack { data } => Ok(data),
+        Message::Error { code, message } => Err(format!("Declined ({}): {}", code, message).into()),
+        reply => Err(format!("Unexpected reply {:?}", reply).into()),
+    }
+}
 
-            },
-            "0001" => {
-                println!("Download starting");
-                let file_name = String::from_utf8_lossy(&buffer[..4]).to_string();
-                handle_file_download(stream, file_name);
-            },
-            "0111" => {
-                println!("Finishing upload..");
-                conn.execute(&format!("UPDATE {} SET stage = {} WHERE ip = {}", table_name, "0000".to_string(), ip), [])?;
+// Upload data, encoded with codec, as file_name to a peer. Calling it again after a broken
+// connection resumes the upload, chunks the peer already has are skipped.
+fn send_file(addr: SocketAddr, file_name: &str, data: &[u8], codec: CodecId) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect(addr)?;
+    let chunk_count = u32::try_from(data.len().div_ceil(UPLOAD_CHUNK_SIZE))?;
+    let init = Message::UploadInit { file_name: file_name.to_string(), size: data.len() as u64, chunk_count, codec };
+    let received = upload_session::decode_offsets(&exchange(&mut stream, &init)?)?;
+
+    for (index, piece) in data.chunks(UPLOAD_CHUNK_SIZE).enumerate() {
+        let offset = (index * UPLOAD_CHUNK_SIZE) as u64;
+        if received.contains(&offset) {
+            continue;
+        }
+        let chunk = Message::UploadChunk { file_name: file_name.to_string(), offset, data: piece.to_vec() };
+        exchange(&mut stream, &chunk)?;
+    }
+    exchange(&mut stream, &Message::UploadCommit { file_name: file_name.to_string() })?;
+    println!("Uploaded '{}' ({} bytes in {} chunks, {} already there)", file_name, data.len(), chunk_count, received.len());
+    Ok(())
+}
 
+// Bytes asked for in one Download message
+const DOWNLOAD_RANGE_SIZE: u32 = 1024 * 1024;
+
+fn fetch_range(stream: &mut TcpStream, file_name: &str, offset: u64, length: u32) -> Result<RangeReply, Box<dyn std::error::Error>> {
+    let request = Message::Download { file_name: file_name.to_string(), offset, length };
+    Ok(RangeReply::parse(&exchange(stream, &request)?)?)
+}
+
+// Download file_name from the peers holding a copy into destination. The first reachable
+// replica gives the size and hash, the remaining ranges are then fetched in parallel, one
+// connection per replica, and written straight into the file. A range a replica fails to
+// deliver goes back in the queue for the others, so a broken transfer only costs the range
+// in flight rather than the whole file, and a replica that doesn't answer within timeout
+// counts as broken. Data is written to destination.part and only renamed once it matches
+// the hash. The ranges written are recorded next to it, so fetching the same file again
+// after a failure only asks for what is missing. Returns the size of the file.
+fn fetch_file(replicas: &[SocketAddr], file_name: &str, destination: &Path, timeout: Duration) -> Result<u64, Box<dyn std::error::Error>> {
+    let failures = std::sync::Mutex::new(Vec::new());
+    let mut first = None;
+    for addr in replicas {
+        let reply = connect_node(addr, timeout).map_err(|e| e.into()).and_then(|mut stream| fetch_range(&mut stream, file_name, 0, DOWNLOAD_RANGE_SIZE));
+        match reply {
+            // The first range can't be longer than asked for or than the file it claims to be from
+            Ok(range) if range.data.len() as u64 > range.file_size.min(u64::from(DOWNLOAD_RANGE_SIZE)) => {
+                failures.lock().unwrap().push(format!("{}: sent {} bytes of a {} byte file", addr, range.data.len(), range.file_size));
             }
-            _ => println!("Unknown stage"),
-        }
-    } else {
-        match current_stage_code {
-            0b1111 => println!("Join request"),
-            0b0111 => println!("Leave request"),
-            0b0011 => {
-                println!("Upload request");
-                if !user_exists {
-                    conn.execute(&format!("INSERT INTO {} values ({}, {})", table_name, ip, "0011".to_string()), [])?;
-                } else {
-                    conn.execute(&format!("UPDATE {} SET stage = {} WHERE ip = {}", table_name, "0011".to_string(), ip), [])?;
-                }
+            Ok(range) if range.data.is_empty() && range.file_size > 0 => {
+                failures.lock().unwrap().push(format!("{}: sent no data", addr));
             }
-            0b0001 => {
-                if !user_exists {
-                    conn.execute(&format!("INSERT INTO {} values ({}, {})", table_name, ip, "0001".to_string()), [])?;
-                } else {
-                    conn.execute(&format!("UPDATE {} SET stage = {} WHERE ip = {}", table_name, "0001".to_string(), ip), [])?;
-                }
+            Ok(range) => {
+                first = Some((*addr, range));
+                break;
             }
-            _ => println!("Unknown request"),
+            Err(e) => failures.lock().unwrap().push(format!("{}: {}", addr, e)),
         }
     }
+    let (first_addr, first) = first.ok_or_else(|| format!("No replica of '{}' answered [{}]", file_name, failures.lock().unwrap().join(", ")))?;
+    let (file_size, file_hash) = (first.file_size, first.file_hash.clone());
+    println!("Downloading '{}' ({} bytes) to {}", file_name, file_size, destination.display());
+
+    let mut part_name = destination.as_os_str().to_owned();
+    part_name.push(".part");
+    let part_path = std::path::PathBuf::from(part_name);
+    let mut progress_name = part_path.as_os_str().to_owned();
+    progress_name.push(".ranges");
+    let progress_path = std::path::PathBuf::from(progress_name);
+
+    // Ranges recorded for a .part file that is gone are no use
+    if !part_path.exists() && progress_path.exists() {
+        fs::remove_file(&progress_path)?;
+    }
+    let (mut progress, mut received) = download::DownloadProgress::open(&progress_path, file_size, &file_hash)?;
+    if !received.is_empty() {
+        println!("Resuming '{}' with {} ranges already received", file_name, received.len());
+    }
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&part_path)?;
+    if received.is_empty() {
+        file.set_len(0)?;
+    }
+    file.write_all(&first.data)?;
+    progress.record(0, first.data.len() as u64)?;
+    received.push((0, first.data.len() as u64));
+    let file = std::sync::Mutex::new((file, progress));
+    let pending = std::sync::Mutex::new(download::RangeQueue::resume(&received, file_size, DOWNLOAD_RANGE_SIZE));
+    let pending_changed = std::sync::Condvar::new();
+    let served_by = std::sync::Mutex::new(vec![first_addr]);
+
+    thread::scope(|scope| {
+        for addr in replicas {
+            let (file, pending, pending_changed, served_by, failures, file_hash) = (&file, &pending, &pending_changed, &served_by, &failures, &file_hash);
+            scope.spawn(move || {
+                let mut stream = match connect_node(addr, timeout) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        failures.lock().unwrap().push(format!("{}: {}", addr, e));
+                        return;
+                    }
+                };
+                loop {
+                    // Only stop once nothing is left, including ranges other workers may hand back
+                    let next = {
+                        let mut queue = pending.lock().unwrap();
+                        loop {
+                            if let Some(range) = queue.pop() {
+                                break Some(range);
+                            }
+                            if queue.in_flight() == 0 {
+                                break None;
+                            }
+                            queue = pending_changed.wait(queue).unwrap();
+                        }
+                    };
+                    let Some((offset, length)) = next else { return };
+                    let range = match fetch_range(&mut stream, file_name, offset, length) {
+                        Ok(range) if range.file_size != file_size || range.file_hash != *file_hash => Err(format!("{} holds a different version of '{}'", addr, file_name).into()),
+                        Ok(range) if range.data.is_empty() || range.data.len() > length as usize => Err(format!("{} sent {} bytes for a {} byte range", addr, range.data.len(), length).into()),
+                        result => result,
+                    };
+                    let written = range.and_then(|range| {
+                        let (file, progress) = &mut *file.lock().unwrap();
+                        file.seek(io::SeekFrom::Start(offset))?;
+                        file.write_all(&range.data)?;
+                        progress.record(offset, range.data.len() as u64)?;
+                        Ok(range.data.len())
+                    });
+                    let failed = match written {
+                        Ok(received) => {
+                            let mut served_by = served_by.lock().unwrap();
+                            if !served_by.contains(addr) {
+                                served_by.push(*addr);
+                            }
+                            // A shorter reply is fine, the rest of the range is asked for again
+                            if received < length as usize {
+                                pending.lock().unwrap().push(offset + received as u64, length - received as u32);
+                            }
+                            false
+                        }
+                        Err(e) => {
+                            eprintln!("Download of bytes {}..{} of '{}' from {} failed: {}", offset, offset + u64::from(length), file_name, addr, e);
+                            failures.lock().unwrap().push(format!("{}: {}", addr, e));
+                            pending.lock().unwrap().push(offset, length);
+                            true
+                        }
+                    };
+                    pending.lock().unwrap().done();
+                    pending_changed.notify_all();
+                    if failed {
+                        return;
+                    }
+                }
+            });
+        }
+    });
 
-    Ok(())
+    let missing = pending.into_inner().unwrap().len();
+    if missing > 0 {
+        let failures = failures.into_inner().unwrap();
+        return Err(format!("{} ranges of '{}' could not be fetched from any replica [{}]", missing, file_name, failures.join(", ")).into());
+    }
+    let (mut file, progress) = file.into_inner().unwrap();
+    drop(progress);
+    file.sync_all()?;
+    file.seek(io::SeekFrom::Start(0))?;
+    let actual = chunk_store::sha256_hex_reader(&mut file)?;
+    fs::remove_file(&progress_path)?;
+    if actual != file_hash {
+        // Nothing in the .part file can be trusted any more, the next try starts over
+        fs::remove_file(&part_path)?;
+        let served_by: Vec<String> = served_by.into_inner().unwrap().iter().map(|addr| addr.to_string()).collect();
+        return Err(format!("Downloaded '{}' is corrupt: expected hash {}, got {} (served by {})", file_name, file_hash, actual, served_by.join(", ")).into());
+    }
+    fs::rename(&part_path, destination)?;
+    Ok(file_size)
 }
 
-fn listen_for_requests() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:3567")?;
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let network_id = "some_id".to_string(); // Example, use real network ID
-                std::thread::spawn(move || {
-                    handle_requests(stream, network_id).unwrap_or_else(|e| eprintln!("Error: {}", e));
-                });
-            }
-            Err(e) => eprintln!("Connection failed: {}", e),
-        }
+
+// Port nodes listen for requests on
+const NODE_PORT: u16 = 3567;
+// How long to wait on a peer before counting the request as failed
+const NODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Nodes are named by their IP in the peer table and in manifests, and listen on NODE_PORT.
+// A name can also carry its own port, as in "10.0.0.7:4000".
+fn node_addr(node: &str) -> Result<SocketAddr, String> {
+    node.parse::<SocketAddr>()
+        .or_else(|_| node.parse::<std::net::IpAddr>().map(|ip| SocketAddr::new(ip, NODE_PORT)))
+        .map_err(|_| format!("'{}' is not a node address", node))
+}
+
+// Connection to a node on which connecting, and every read and write after, gives up
+// after timeout
+fn connect_node(addr: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+// Send one request to a node and return the data of its Ack
+fn request_node(node: &str, message: &Message) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut stream = connect_node(&node_addr(node)?, NODE_TIMEOUT)?;
+    exchange(&mut stream, message)
+}
+
+// Have node hold a replica of an encoded chunk or parity shard of file_name. The local
+// node already keeps every chunk of the files uploaded from it.
+fn put_replica(node: &str, file_name: &str, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    if node == local_node() {
+        return Ok(());
+    }
+    let hash = String::from_utf8(request_node(node, &Message::StoreChunk { file_name: file_name.to_string(), data: data.to_vec() })?)?;
+    let expected = chunk_store::sha256_hex(data);
+    if hash != expected {
+        return Err(format!("Node {} stored {} as {}", node, expected, hash).into());
     }
     Ok(())
 }
 
-struct Request {
-    ip: SocketAddr,
-    message: String,
+fn fetch_replica(node: &str, hash: &str) -> Result<Vec<u8>, String> {
+    if node == local_node() {
+        return ChunkStore::new(LOCAL_CHUNK_STORE).get(hash).map_err(|e| e.to_string());
+    }
+    request_node(node, &Message::FetchChunk { hash: hash.to_string() }).map_err(|e| e.to_string())
 }
 
-impl Request {
-    fn sendRequest(&self) {
-        let mut stream = TcpStream::connect(self.ip)
-                       .expect("Couldn't connect to the server...");
-        stream.write(self.message.as_bytes()).expect("Failed to write message");
+// Store an encoded chunk or parity shard locally and send it to its replica peers, returns
+// its hash and the nodes holding it. A peer that can't take it is skipped for the next one
+// in rendezvous order; when too few peers answer the chunk is kept under-replicated and
+// left to the repair daemon.
+fn store_replicas(conn: &Connection, chunk_store: &ChunkStore, file_name: &str, data: &[u8], peers: &[String], replication_factor: usize) -> Result<(String, Vec<String>), Box<dyn std::error::Error>> {
+    if replication_factor == 0 {
+        return Err("Replication factor must be at least 1".into());
+    }
+    let hash = chunk_store.put(conn, data)?;
+
+    let mut nodes = Vec::with_capacity(replication_factor);
+    for node in replication::rank_peers(&hash, peers) {
+        if nodes.len() >= replication_factor {
+            break;
+        }
+        match put_replica(&node, file_name, data) {
+            Ok(()) => nodes.push(node),
+            Err(e) => eprintln!("Unable to store {} on {}: {}", hash, node, e),
+        }
     }
+    if nodes.is_empty() {
+        return Err(format!("No peer could store {}", hash).into());
+    }
+    if nodes.len() < replication_factor {
+        eprintln!("{} is only on {} of {} peers", hash, nodes.len(), replication_factor);
+    }
+    Ok((hash, nodes))
 }
 
+// Ask the peers holding replicas of a replaced or deleted manifest to drop the ones the
+// current manifest doesn't keep on them. A peer that can't be reached keeps its copy, which
+// costs space but never data. Replicas repair copied onto this node are released here.
+fn release_replicas(conn: &Connection, previous: &Manifest, current: Option<&Manifest>) {
+    let kept = current.map(Manifest::replica_locations).unwrap_or_default();
+    for (node, hash) in previous.replica_locations() {
+        if kept.contains(&(node, hash)) {
+            continue;
+        }
+        if node == local_node() {
+            if let Err(e) = ChunkStore::new(LOCAL_CHUNK_STORE).release_replica(conn, local_node(), &previous.file_name, hash) {
+                eprintln!("Unable to release {} of '{}' here: {}", hash, previous.file_name, e);
+            }
+            continue;
+        }
+        let release = Message::ReleaseChunk { file_name: previous.file_name.clone(), hash: hash.to_string() };
+        if let Err(e) = request_node(node, &release) {
+            eprintln!("Unable to release {} of '{}' on {}: {}", hash, previous.file_name, node, e);
+        }
+    }
+}
 
-fn upload(file_path:&str) {
-    let text = fs::read_to_string(file_path).expect("Unable to read text within file");
+// Drop a file uploaded from this node, here and on the peers holding its replicas
+fn delete(file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = Connection::open("pointers.db")?;
+    ChunkStore::create_tables(&conn)?;
+    let previous = ChunkStore::new(LOCAL_CHUNK_STORE).delete_file(&mut conn, file_name)?;
+    match previous {
+        Some(previous) => release_replicas(&conn, &previous, None),
+        None => return Err(format!("No file named '{}' was uploaded", file_name).into()),
+    }
+    Ok(())
+}
 
-    let slicer = Slicer {
-        text:text.clone(),
-        slice_amount: 3,
-    };
-    let slices = slicer.slice();
+// Every interval, send a join request to every known peer so they keep counting this node as
+// live. A peer that answers is refreshed here as well.
+fn start_heartbeat(interval: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        let result = Connection::open("pointers.db").map_err(|e| e.into()).and_then(|conn| {
+            replication::create_tables(&conn)?;
+            for peer in replication::known_peers(&conn)?.iter().filter(|peer| peer.as_str() != local_node()) {
+                match request_node(peer, &Message::Join) {
+                    Ok(_) => replication::add_peer(&conn, peer)?,
+                    Err(e) => eprintln!("Heartbeat to {} failed: {}", peer, e),
+                }
+            }
+            Ok::<(), Box<dyn std::error::Error>>(())
+        });
+        if let Err(e) = result {
+            eprintln!("Heartbeat failed: {}", e);
+        }
+        thread::sleep(interval);
+    })
+}
 
-    let compiler = Compiler {
-        slices
+// Every interval, copy the replicas held by peers that left or went quiet onto live peers.
+// Copies go through this node: fetched from a live holder, then stored on the new one. A
+// copy kept on this node is held like a replica for a peer, until release_replicas drops it.
+fn start_repair_daemon(interval: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        let result = Connection::open("pointers.db").map_err(|e| e.into()).and_then(|conn| {
+            ChunkStore::create_tables(&conn)?;
+            replication::create_tables(&conn)?;
+            let live = replication::live_peers(&conn, replication::HEARTBEAT_TIMEOUT)?;
+            let suspect = replication::suspect_peers(&conn, replication::HEARTBEAT_TIMEOUT, replication::PEER_EXPIRY)?;
+            replication::repair(&conn, &live, &suspect, |file_name, from, to, hash| {
+                let data = fetch_replica(from, hash)?;
+                if chunk_store::sha256_hex(&data) != hash {
+                    return Err(format!("{} served by node {} is corrupt", hash, from));
+                }
+                if to == local_node() {
+                    return ChunkStore::new(LOCAL_CHUNK_STORE).hold_replica(&conn, local_node(), file_name, &data).map(|_| ()).map_err(|e| e.to_string());
+                }
+                put_replica(to, file_name, &data).map_err(|e| e.to_string())
+            })
+        });
+        match result {
+            Ok(report) => println!(
+                "Repair pass: {} replicas checked, {} copied, {} under-replicated, {} lost",
+                report.replicas_checked, report.replicas_copied, report.under_replicated, report.lost
+            ),
+            Err(e) => eprintln!("Repair pass failed: {}", e),
+        }
+        thread::sleep(interval);
+    })
+}
+
+// The file is read as a stream: only the chunk being stored and the encoded chunks of the
+// current stripe are held in memory
+fn upload(file_path:&str, codec_id: Option<CodecId>, erasure: Option<ErasureLayout>, peers: &[String], replication_factor: usize, key_source: Option<&KeySource>) {
+    let file = File::open(file_path).expect("Unable to read file");
+    let file_name = Path::new(file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| file_path.to_string());
+
+    let mut conn = Connection::open("pointers.db").expect("Unable to open pointer database");
+    ChunkStore::create_tables(&conn).expect("Unable to create chunk tables");
+    let chunk_store = ChunkStore::new(LOCAL_CHUNK_STORE);
+
+    let (encryption, cipher) = match key_source {
+        Some(key_source) => {
+            let (encryption, cipher) = ChunkCipher::for_upload(key_source).expect("Unable to get an encryption key");
+            (encryption, Some(cipher))
+        }
+        None => (Encryption::None, None),
     };
-    let result = compiler.compile();
-    println!("Original text {}", result);
 
-    //for slice in slices.iter() {
-    //    let compressor_struct = Compressor { text: text.clone() };
-    //    let (encoded_text, encoding_table) = compressor_struct.compress();
-    //    println!("{}", encoded_text);
-    //}
+    // Encode every chunk with the codec chosen for this upload, or with whichever codec
+    // gives the smallest result when none was chosen, encrypt it when the file has a key
+    // and store it under its hash. Each stored chunk is decoded again to check it gives
+    // back the original bytes.
+    let mut chunk_entries = Vec::new();
+    let mut parity = Vec::new();
+    let mut stripe_chunks = Vec::new();
+    let mut file_hasher = Sha256::new();
+    let mut size = 0u64;
+    let chunks = cdc::ChunkReader::new(io::BufReader::new(file), ChunkSizes::DEFAULT);
+    for (index, chunk) in chunks.enumerate() {
+        let chunk = Chunk {
+            index,
+            offset: size,
+            data: chunk.expect("Unable to read file"),
+        };
+        file_hasher.update(&chunk.data);
+        size += chunk.data.len() as u64;
+
+        let (chunk_codec, encoded_chunk) = match codec_id {
+            Some(codec_id) => (codec_id, codec_id.codec().encode(&chunk.data)),
+            None => codec::encode_smallest(&chunk.data),
+        };
+        let encoded_chunk = match &cipher {
+            Some(cipher) => cipher.encrypt_chunk(chunk.offset, &encoded_chunk).expect("Unable to encrypt chunk"),
+            None => encoded_chunk,
+        };
+        let (hash, nodes) = store_replicas(&conn, &chunk_store, &file_name, &encoded_chunk, peers, replication_factor).expect("Unable to store chunk");
+        println!("Chunk {} at offset {} ({} bytes) encoded to {} bytes with {}: {} on [{}]", chunk.index, chunk.offset, chunk.data.len(), encoded_chunk.len(), chunk_codec, hash, nodes.join(", "));
+
+        let stored = chunk_store.get(&hash).expect("Unable to read back stored chunk");
+        let decoded = match &cipher {
+            Some(cipher) => cipher.decrypt_chunk(&hash, chunk.offset, &stored).expect("Unable to decrypt stored chunk"),
+            None => stored,
+        };
+        let decoded = chunk_codec.codec().decode(&decoded).expect("Unable to decode stored chunk");
+        assert!(decoded == chunk.data, "Chunk {} doesn't decode to the original bytes", chunk.index);
+
+        chunk_entries.push(ChunkEntry {
+            offset: chunk.offset,
+            size: chunk.data.len() as u64,
+            stored_size: encoded_chunk.len() as u64,
+            hash,
+            codec: chunk_codec,
+            nodes,
+        });
+
+        // Parity is computed over the encoded chunks, so a lost chunk is rebuilt exactly as stored
+        if let Some(layout) = erasure {
+            stripe_chunks.push(encoded_chunk);
+            if stripe_chunks.len() == layout.data_shards {
+                store_parity(&conn, &chunk_store, &file_name, layout, &stripe_chunks, peers, replication_factor, &mut parity);
+                stripe_chunks.clear();
+            }
+        }
+    }
+    if let Some(layout) = erasure {
+        if !stripe_chunks.is_empty() {
+            store_parity(&conn, &chunk_store, &file_name, layout, &stripe_chunks, peers, replication_factor, &mut parity);
+        }
+    }
 
-    // Compress the text
-    
-    // Create a decoder and decode the text
-    // let decoder_struct = Decoder {
-    //    encoded_text,
-    //    encoding_table,
-    //};
-    //decoder_struct.decode();
+    let manifest = Manifest {
+        file_name: file_name.clone(),
+        size,
+        hash: format!("{:x}", file_hasher.finalize()),
+        replication_factor,
+        encryption,
+        chunks: chunk_entries,
+        erasure,
+        parity,
+    };
+    let previous = chunk_store.save_file(&mut conn, &manifest).expect("Unable to save file manifest");
+    if let (Some(key_source), Some(cipher)) = (key_source, &cipher) {
+        cipher.keep_key(key_source, &file_name).expect("Unable to keep the encryption key");
+    }
+    if let Some(previous) = previous {
+        release_replicas(&conn, &previous, Some(&manifest));
+    }
+    println!("Uploaded '{}' ({} bytes in {} chunks)", file_name, manifest.size, manifest.chunks.len());
+}
 
+// Compute the parity shards of one stripe of encoded chunks, store them and add them to parity
+#[allow(clippy::too_many_arguments)]
+fn store_parity(conn: &Connection, chunk_store: &ChunkStore, file_name: &str, layout: ErasureLayout, stripe_chunks: &[Vec<u8>], peers: &[String], replication_factor: usize, parity: &mut Vec<ShardEntry>) {
+    let stripe = parity.len() / layout.parity_shards;
+    let stripe_chunks: Vec<&[u8]> = stripe_chunks.iter().map(|chunk| chunk.as_slice()).collect();
+    for shard in layout.parity(&stripe_chunks).expect("Unable to compute parity shards") {
+        let (hash, nodes) = store_replicas(conn, chunk_store, file_name, &shard, peers, replication_factor).expect("Unable to store parity shard");
+        println!("Parity shard of stripe {} ({} bytes): {} on [{}]", stripe, shard.len(), hash, nodes.join(", "));
+        parity.push(ShardEntry {
+            stored_size: shard.len() as u64,
+            hash,
+            nodes,
+        });
+    }
 }
 
 fn clean_file_path(input: &str) -> String {
@@ -621,7 +1401,11 @@ fn main() {
     //        let file_path = file_path.trim();  // Trim the newline characters
     //        let clean_file_path = clean_file_path(file_path);
     //        println!("You selected the file: {file_path} to upload.");
-    //        upload(&clean_file_path);
+    //        let conn = Connection::open("pointers.db").expect("Unable to open pointer database");
+    //        replication::create_tables(&conn).expect("Unable to create peer table");
+    //        let peers = replication::known_peers(&conn).expect("Unable to read peers");
+    //        let keyring = encryption::Keyring::open("keyring.db").expect("Unable to open keyring");
+    //        upload(&clean_file_path, None, Some(ErasureLayout::DEFAULT), &peers, replication::DEFAULT_REPLICATION_FACTOR, Some(&KeySource::Keyring(&keyring)));
     //    } else if choice == "2" {
     //        println!("Choose a file to download:");
     //        io::stdin().read_line(&mut file_path).expect("Sorry, unable to read your input");
@@ -640,6 +1424,462 @@ fn main() {
     //    secondary_port: 5439,
     //    response: None,
     //};
-    //reciever.receive();
+    //start_heartbeat(replication::HEARTBEAT_INTERVAL);
+    //start_repair_daemon(Duration::from_secs(60));
+    //let runtime = tokio::runtime::Runtime::new().expect("Unable to start the runtime");
+    //runtime.block_on(reciever.receive(async {
+    //    tokio::signal::ctrl_c().await.expect("Unable to listen for ctrl-c");
+    //})).expect("Server failed");
     //println!("Test");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn leaves(alphabet_size: usize) -> Vec<Node> {
+        (0..alphabet_size)
+            .map(|i| Node {
+                frequency: ((i * 7919) % 1000 + 1) as i64,
+                symbol: Some(i as u8),
+                left: None,
+                right: None,
+            })
+            .collect()
+    }
+
+    fn depths(node: &Node, depth: usize, out: &mut Vec<usize>) {
+        if node.symbol.is_some() {
+            out.push(depth);
+        }
+        if let Some(ref left) = node.left {
+            depths(left, depth + 1, out);
+        }
+        if let Some(ref right) = node.right {
+            depths(right, depth + 1, out);
+        }
+    }
+
+    // Answer every Download on one connection with reply(offset, length), until the
+    // client hangs up
+    fn stub_replica<F>(reply: F) -> SocketAddr
+    where
+        F: Fn(u64, u32) -> Message + Send + Sync + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reply = Arc::new(reply);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (mut stream, reply) = (stream.unwrap(), reply.clone());
+                thread::spawn(move || {
+                    while let Ok(Message::Download { offset, length, .. }) = Message::read_from(&mut stream) {
+                        if reply(offset, length).write_to(&mut stream).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn range_reply(data: &[u8], offset: u64, length: u32) -> Message {
+        let start = (offset as usize).min(data.len());
+        let end = (start + length as usize).min(data.len());
+        let range = RangeReply { file_size: data.len() as u64, file_hash: chunk_store::sha256_hex(data), data: data[start..end].to_vec() };
+        Message::Ack { data: range.to_bytes() }
+    }
+
+    fn download_path(name: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("dstorage-download-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory.join(name)
+    }
+
+    #[test]
+    fn fetch_file_rejects_a_first_range_longer_than_the_file() {
+        let liar = stub_replica(|_, _| {
+            let range = RangeReply { file_size: 1, file_hash: String::new(), data: vec![7; 10] };
+            Message::Ack { data: range.to_bytes() }
+        });
+        let huge = stub_replica(|_, _| {
+            let range = RangeReply { file_size: u64::MAX, file_hash: String::new(), data: Vec::new() };
+            Message::Ack { data: range.to_bytes() }
+        });
+        let destination = download_path("liar.bin");
+        assert!(fetch_file(&[liar, huge], "liar.bin", &destination, NODE_TIMEOUT).is_err());
+        assert!(!destination.exists());
+
+        let data: Vec<u8> = (0..3 * DOWNLOAD_RANGE_SIZE as usize + 11).map(|i| (i % 251) as u8).collect();
+        let honest = stub_replica(move |offset, length| range_reply(&data, offset, length));
+        let destination = download_path("honest.bin");
+        assert_eq!(fetch_file(&[honest], "honest.bin", &destination, NODE_TIMEOUT).unwrap(), 3 * DOWNLOAD_RANGE_SIZE as u64 + 11);
+        assert_eq!(fs::read(&destination).unwrap().len(), 3 * DOWNLOAD_RANGE_SIZE as usize + 11);
+    }
+
+    #[test]
+    fn fetch_file_retries_ranges_a_replica_drops_after_the_others_ran_out() {
+        let data: Vec<u8> = (0..4 * DOWNLOAD_RANGE_SIZE as usize).map(|i| (i % 241) as u8).collect();
+        let expected = data.clone();
+        let honest = stub_replica(move |offset, length| range_reply(&data, offset, length));
+        let slow_failure = stub_replica(|_, _| {
+            thread::sleep(Duration::from_millis(300));
+            Message::error(ErrorCode::Internal, "Disk went away")
+        });
+
+        let destination = download_path("slow.bin");
+        fetch_file(&[honest, slow_failure], "slow.bin", &destination, NODE_TIMEOUT).unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), expected);
+    }
+
+    fn part_files(destination: &Path) -> [std::path::PathBuf; 2] {
+        let part = format!("{}.part", destination.display());
+        [std::path::PathBuf::from(&part), std::path::PathBuf::from(format!("{}.ranges", part))]
+    }
+
+    #[test]
+    fn fetch_file_resumes_with_the_ranges_it_is_missing() {
+        let data: Vec<u8> = (0..6 * DOWNLOAD_RANGE_SIZE as usize + 11).map(|i| (i % 233) as u8).collect();
+        let destination = download_path("resumed.bin");
+        for path in part_files(&destination) {
+            let _ = fs::remove_file(path);
+        }
+
+        // The only replica breaks down after three ranges
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (served, counted) = (data.clone(), requests.clone());
+        let failing = stub_replica(move |offset, length| match counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+            0..3 => range_reply(&served, offset, length),
+            _ => Message::error(ErrorCode::Internal, "Disk went away"),
+        });
+        assert!(fetch_file(&[failing], "resumed.bin", &destination, NODE_TIMEOUT).is_err());
+        assert!(part_files(&destination).iter().all(|path| path.exists()));
+
+        // The first range gives the size and hash again, then only the four missing ones follow
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (served, counted) = (data.clone(), requests.clone());
+        let honest = stub_replica(move |offset, length| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            range_reply(&served, offset, length)
+        });
+        fetch_file(&[honest], "resumed.bin", &destination, NODE_TIMEOUT).unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1 + 4);
+        assert_eq!(fs::read(&destination).unwrap(), data);
+        assert!(part_files(&destination).iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn fetch_file_starts_over_when_the_part_file_cant_be_used() {
+        let data: Vec<u8> = (0..3 * DOWNLOAD_RANGE_SIZE as usize).map(|i| (i % 229) as u8).collect();
+        let honest = {
+            let data = data.clone();
+            stub_replica(move |offset, length| range_reply(&data, offset, length))
+        };
+        let destination = download_path("restarted.bin");
+        let [part, progress] = part_files(&destination);
+
+        // Left by a download of another version of the file
+        fs::write(&part, vec![1u8; 5 * DOWNLOAD_RANGE_SIZE as usize]).unwrap();
+        fs::write(&progress, format!("{} {}\n0 {}\n", 5 * DOWNLOAD_RANGE_SIZE, chunk_store::sha256_hex(b"old"), 5 * DOWNLOAD_RANGE_SIZE)).unwrap();
+        fetch_file(&[honest], "restarted.bin", &destination, NODE_TIMEOUT).unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), data);
+
+        // Recorded as received but never written: caught by the hash, then fetched in full
+        fs::write(&part, vec![0u8; data.len()]).unwrap();
+        fs::write(&progress, format!("{} {}\n0 {}\n", data.len(), chunk_store::sha256_hex(&data), data.len())).unwrap();
+        let error = fetch_file(&[honest], "restarted.bin", &destination, NODE_TIMEOUT).unwrap_err().to_string();
+        assert!(error.contains("is corrupt"), "{}", error);
+        assert!(!part.exists() && !progress.exists());
+        fetch_file(&[honest], "restarted.bin", &destination, NODE_TIMEOUT).unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), data);
+    }
+
+    // A replica that takes connections and requests but never answers
+    fn stalled_replica() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Connections are kept open until the test ends, never read from
+        thread::spawn(move || listener.incoming().collect::<Vec<_>>());
+        addr
+    }
+
+    #[test]
+    fn fetch_file_moves_on_from_a_replica_that_stops_answering() {
+        let data: Vec<u8> = (0..4 * DOWNLOAD_RANGE_SIZE as usize).map(|i| (i % 239) as u8).collect();
+        let expected = data.clone();
+        // Hashed once up front, so the honest replica answers well within the timeout
+        let file_hash = chunk_store::sha256_hex(&data);
+        let honest = stub_replica(move |offset, length| {
+            let end = (offset as usize + length as usize).min(data.len());
+            let range = RangeReply { file_size: data.len() as u64, file_hash: file_hash.clone(), data: data[offset as usize..end].to_vec() };
+            Message::Ack { data: range.to_bytes() }
+        });
+        let timeout = Duration::from_secs(1);
+
+        let destination = download_path("stalled.bin");
+        let started = std::time::Instant::now();
+        fetch_file(&[stalled_replica(), honest], "stalled.bin", &destination, timeout).unwrap();
+        assert!(started.elapsed() < 5 * timeout, "took {:?}", started.elapsed());
+        assert_eq!(fs::read(&destination).unwrap(), expected);
+
+        let destination = download_path("all_stalled.bin");
+        let error = fetch_file(&[stalled_replica()], "all_stalled.bin", &destination, timeout).unwrap_err().to_string();
+        assert!(error.contains("No replica of 'all_stalled.bin' answered"), "{}", error);
+    }
+
+    fn report(lines: usize) -> Vec<u8> {
+        (0..lines).flat_map(|i| format!("Line {} of the report, {} squared is {}\n", i, i, i * i).into_bytes()).collect()
+    }
+
+    // Encode data the way upload does, in chunks of chunk_size, into a manifest and the
+    // stored chunks and parity shards it lists, keyed by hash
+    fn stored_file(data: &[u8], chunk_size: usize, erasure: Option<ErasureLayout>, encryption: Encryption, cipher: Option<&ChunkCipher>) -> (Manifest, HashMap<String, Vec<u8>>) {
+        let mut stored = HashMap::new();
+        let mut chunks = Vec::new();
+        let mut encoded_chunks = Vec::new();
+        for chunk in (Slicer { data: data.to_vec(), mode: SliceMode::FixedSize(chunk_size) }).slice() {
+            let (chunk_codec, encoded_chunk) = codec::encode_smallest(&chunk.data);
+            let encoded_chunk = match cipher {
+                Some(cipher) => cipher.encrypt_chunk(chunk.offset, &encoded_chunk).unwrap(),
+                None => encoded_chunk,
+            };
+            let hash = chunk_store::sha256_hex(&encoded_chunk);
+            chunks.push(ChunkEntry {
+                offset: chunk.offset,
+                size: chunk.data.len() as u64,
+                stored_size: encoded_chunk.len() as u64,
+                hash: hash.clone(),
+                codec: chunk_codec,
+                nodes: vec!["10.0.0.1".into(), "10.0.0.2".into()],
+            });
+            stored.insert(hash, encoded_chunk.clone());
+            encoded_chunks.push(encoded_chunk);
+        }
+
+        let mut parity = Vec::new();
+        if let Some(layout) = erasure {
+            for stripe in encoded_chunks.chunks(layout.data_shards) {
+                let stripe: Vec<&[u8]> = stripe.iter().map(Vec::as_slice).collect();
+                for shard in layout.parity(&stripe).unwrap() {
+                    let hash = chunk_store::sha256_hex(&shard);
+                    parity.push(ShardEntry { stored_size: shard.len() as u64, hash: hash.clone(), nodes: vec!["10.0.0.3".into()] });
+                    stored.insert(hash, shard);
+                }
+            }
+        }
+
+        let manifest = Manifest {
+            file_name: "report.txt".into(),
+            size: data.len() as u64,
+            hash: chunk_store::sha256_hex(data),
+            replication_factor: 2,
+            encryption,
+            chunks,
+            erasure,
+            parity,
+        };
+        (manifest, stored)
+    }
+
+    // Rebuild the file of manifest from the chunks in stored, whichever node is asked
+    fn reassemble(manifest: &Manifest, cipher: Option<&ChunkCipher>, stored: &HashMap<String, Vec<u8>>) -> Result<Vec<u8>, String> {
+        Compiler::from_manifest(manifest, cipher, |node, hash| stored.get(hash).cloned().ok_or_else(|| format!("No {} on {}", hash, node)))?
+            .compile_verified(manifest)
+    }
+
+    #[test]
+    fn rebuilds_a_file_from_a_manifest_sent_to_another_node() {
+        let data = report(200);
+        let (encryption, cipher) = ChunkCipher::for_upload(&KeySource::Passphrase("correct horse")).unwrap();
+        let (manifest, stored) = stored_file(&data, 1000, Some(ErasureLayout::DEFAULT), encryption, Some(&cipher));
+        assert!(manifest.chunks.len() > ErasureLayout::DEFAULT.data_shards);
+
+        // The receiving node only has the bytes of the manifest and the passphrase
+        let received = Manifest::parse(&manifest.to_bytes().unwrap()).unwrap();
+        assert_eq!(received, manifest);
+        let cipher = ChunkCipher::for_download(Some(&KeySource::Passphrase("correct horse")), &received.file_name, &received.encryption).unwrap();
+        assert_eq!(reassemble(&received, cipher.as_ref(), &stored).unwrap(), data);
+
+        let wrong = ChunkCipher::for_download(Some(&KeySource::Passphrase("wrong horse")), &received.file_name, &received.encryption).unwrap();
+        assert!(reassemble(&received, wrong.as_ref(), &stored).is_err());
+        assert!(reassemble(&received, None, &stored).unwrap_err().contains("no key"));
+    }
+
+    #[test]
+    fn rebuilds_lost_chunks_from_parity() {
+        let data = report(120);
+        let layout = ErasureLayout::DEFAULT;
+        let (manifest, mut stored) = stored_file(&data, 1000, Some(layout), Encryption::None, None);
+        assert!(manifest.chunks.len() > layout.data_shards && manifest.chunks.len() % layout.data_shards != 0);
+
+        // One data chunk and one parity shard of the first stripe, and a chunk of the short last stripe
+        stored.remove(&manifest.chunks[1].hash);
+        stored.remove(&manifest.parity[0].hash);
+        stored.remove(&manifest.chunks.last().unwrap().hash);
+        assert_eq!(reassemble(&manifest, None, &stored).unwrap(), data);
+
+        // A full stripe can't lose more than its parity shards
+        stored.remove(&manifest.chunks[2].hash);
+        assert!(reassemble(&manifest, None, &stored).unwrap_err().contains("Stripe 0"));
+    }
+
+    #[test]
+    fn rejects_corrupt_and_missing_chunks() {
+        let data = report(120);
+        let (manifest, stored) = stored_file(&data, 1000, None, Encryption::None, None);
+        assert_eq!(reassemble(&manifest, None, &stored).unwrap(), data);
+
+        // A corrupt copy is skipped for the next node, with none left the file can't be rebuilt
+        let target = manifest.chunks[2].hash.clone();
+        let corrupt_on = |corrupt_nodes: &[&str]| {
+            Compiler::from_manifest(&manifest, None, |node, hash| {
+                let mut data = stored[hash].clone();
+                if hash == target && corrupt_nodes.contains(&node) {
+                    data[0] ^= 0x01;
+                }
+                Ok(data)
+            })
+            .and_then(|compiler| compiler.compile_verified(&manifest))
+        };
+        assert_eq!(corrupt_on(&["10.0.0.1"]).unwrap(), data);
+        let error = corrupt_on(&["10.0.0.1", "10.0.0.2"]).unwrap_err();
+        assert!(error.contains(&format!("Chunk 2 ({}) is not available from any node", target)), "{}", error);
+
+        let mut missing = stored.clone();
+        missing.remove(&manifest.chunks[3].hash);
+        let error = reassemble(&manifest, None, &missing).unwrap_err();
+        assert!(error.contains("Chunk 3") && error.contains("No "), "{}", error);
+
+        // Chunks that all check out still have to add up to the file the manifest describes
+        let wrong_size = Manifest { size: manifest.size + 1, ..manifest.clone() };
+        assert!(reassemble(&wrong_size, None, &stored).unwrap_err().contains("after reassembly"));
+        let wrong_hash = Manifest { hash: chunk_store::sha256_hex(b"another file"), ..manifest.clone() };
+        assert!(reassemble(&wrong_hash, None, &stored).is_err());
+    }
+
+    #[test]
+    fn stores_under_replicated_when_too_few_peers_are_known() {
+        let root = std::env::temp_dir().join(format!("dstorage-replicas-{}", std::process::id()));
+        let conn = Connection::open_in_memory().unwrap();
+        ChunkStore::create_tables(&conn).unwrap();
+        let chunk_store = ChunkStore::new(&root);
+
+        let peers = vec![local_node().to_string()];
+        let (hash, nodes) = store_replicas(&conn, &chunk_store, "report.txt", b"chunk", &peers, 3).unwrap();
+        assert_eq!(nodes, peers);
+        assert!(chunk_store.contains(&hash));
+        assert!(store_replicas(&conn, &chunk_store, "report.txt", b"chunk", &peers, 0).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // file_pointers as the first version of the node created it
+    fn old_pointer_table() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE file_pointers (
+                id INTEGER PRIMARY KEY,
+                ip TEXT NOT NULL,
+                fileName TEXT NOT NULL,
+                dictionaryInPlace TEXT NOT NULL,
+                encodedTextInPlace TEXT NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO file_pointers (ip, fileName, dictionaryInPlace, encodedTextInPlace) VALUES ('10.0.0.7', 'notes.txt', 'TRUE', 'TRUE')",
+            [],
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn adds_the_codec_column_to_old_pointer_tables() {
+        let conn = old_pointer_table();
+        create_pointer_table(&conn).unwrap();
+        create_pointer_table(&conn).unwrap();
+        let codec: CodecId = conn.query_row("SELECT codec FROM file_pointers WHERE fileName = 'notes.txt'", [], |row| row.get(0)).unwrap();
+        assert_eq!(codec, CodecId::Huffman);
+    }
+
+    #[test]
+    fn turns_text_flags_of_old_pointer_tables_into_integers() {
+        let conn = old_pointer_table();
+        conn.execute(
+            "INSERT INTO file_pointers (ip, fileName, dictionaryInPlace, encodedTextInPlace) VALUES ('10.0.0.7', 'draft.txt', 'FALSE', 'TRUE')",
+            [],
+        )
+        .unwrap();
+        create_pointer_table(&conn).unwrap();
+        create_pointer_table(&conn).unwrap();
+
+        assert!(pointer_columns(&conn).unwrap().iter().all(|(name, kind)| !name.ends_with("InPlace") || kind == "INTEGER"));
+        let flags = |file_name: &str| -> (i64, bool, bool) {
+            conn.query_row(
+                "SELECT id, dictionaryInPlace, encodedTextInPlace FROM file_pointers WHERE fileName = ?1",
+                params![file_name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+        };
+        assert_eq!(flags("notes.txt"), (1, true, true));
+        assert_eq!(flags("draft.txt"), (2, false, true));
+    }
+
+    #[test]
+    fn count_slicing_never_gives_empty_slices() {
+        let slice = |data: &[u8], count| Slicer { data: data.to_vec(), mode: SliceMode::Count(count) }.slice();
+
+        let chunks = slice(b"abc", 5);
+        assert_eq!(chunks.iter().map(|chunk| chunk.data.clone()).collect::<Vec<_>>(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(chunks.iter().map(|chunk| chunk.offset).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let chunks = slice(b"abcdefg", 3);
+        assert_eq!(chunks.iter().map(|chunk| chunk.data.len()).collect::<Vec<_>>(), vec![2, 2, 3]);
+
+        assert!(slice(b"", 4).is_empty());
+    }
+
+    #[test]
+    fn fixed_size_slicing_leaves_the_remainder_to_the_last_slice() {
+        let slice = |data: &[u8], size| Slicer { data: data.to_vec(), mode: SliceMode::FixedSize(size) }.slice();
+
+        let chunks = slice(b"abcdefg", 3);
+        assert_eq!(chunks.iter().map(|chunk| chunk.data.clone()).collect::<Vec<_>>(), vec![b"abc".to_vec(), b"def".to_vec(), b"g".to_vec()]);
+        assert_eq!(chunks.iter().map(|chunk| (chunk.index, chunk.offset)).collect::<Vec<_>>(), vec![(0, 0), (1, 3), (2, 6)]);
+
+        assert_eq!(slice(b"abcdef", 3).len(), 2);
+        assert_eq!(slice(b"abc", 10).len(), 1);
+        assert!(slice(b"", 3).is_empty());
+        assert!(slice(b"abc", 0).is_empty());
+    }
+
+    // Run with: cargo test --release -- --ignored --nocapture bench_tree_construction
+    #[test]
+    #[ignore]
+    fn bench_tree_construction() {
+        let compressor = Compressor { data: Vec::new() };
+        for alphabet_size in [256, 1024, 4096, 16384] {
+            let start = Instant::now();
+            let linear_tree = compressor.merge_smallest_branches(leaves(alphabet_size));
+            let linear_time = start.elapsed();
+
+            let start = Instant::now();
+            let heap_tree = compressor.build_tree(leaves(alphabet_size));
+            let heap_time = start.elapsed();
+
+            let (mut linear_depths, mut heap_depths) = (Vec::new(), Vec::new());
+            depths(&linear_tree, 0, &mut linear_depths);
+            depths(&heap_tree, 0, &mut heap_depths);
+            assert_eq!(linear_depths, heap_depths);
+
+            println!(
+                "{:>6} symbols: linear scan {:>10.3?}, binary heap {:>10.3?}",
+                alphabet_size, linear_time, heap_time
+            );
+        }
+    }
+}
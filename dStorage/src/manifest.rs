@@ -0,0 +1,520 @@
+use std::collections::BTreeMap;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::checksum::crc32;
+use crate::codec::CodecId;
+use crate::encryption::{Encryption, SALT_LEN};
+use crate::erasure::ErasureLayout;
+use crate::wire::{write_string, ByteReader};
+
+// Wire layout of a manifest (integers big-endian, strings are a u16 length then UTF-8):
+//
+//   magic        4 bytes   "DSMF"
+//   version      1 byte    MANIFEST_VERSION
+//   file name    string
+//   file size    8 bytes
+//   file hash    32 bytes  SHA-256 of the whole file
+//   replication  1 byte    number of nodes each chunk and parity shard should be on
+//   encryption   1 byte    0 none, 1 passphrase (followed by a 16 byte salt), 2 keyring,
+//                          3 convergent
+//   chunk count  4 bytes
+//   chunks       offset (u64), size before encoding (u64), stored size (u64), SHA-256 of
+//                the stored chunk (32 bytes), codec (u8), node count (u8), node strings
+//   erasure      data shards (u8, 0 when the file has no parity), parity shards (u8)
+//   parity count 4 bytes
+//   parity       stored size (u64), SHA-256 (32 bytes), node count (u8), node strings
+//   checksum     4 bytes   CRC-32 of everything before it
+pub const MANIFEST_MAGIC: &[u8; 4] = b"DSMF";
+pub const MANIFEST_VERSION: u8 = 4;
+const HASH_LEN: usize = 32;
+// Name of a manifest in errors of the wire helpers
+const WHAT: &str = "Manifest";
+
+// One chunk of a file: where it goes, what it hashes to once stored, how it's encoded and
+// which nodes host it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkEntry {
+    pub offset: u64,
+    pub size: u64,
+    pub stored_size: u64,
+    pub hash: String,
+    pub codec: CodecId,
+    pub nodes: Vec<String>,
+}
+
+// A parity shard, the parity of stripe s starts at parity[s * parity_shards], a short last
+// stripe has fewer (see ErasureLayout::parity_shards_for)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardEntry {
+    pub stored_size: u64,
+    pub hash: String,
+    pub nodes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub file_name: String,
+    pub size: u64,
+    pub hash: String,
+    pub replication_factor: usize,
+    pub encryption: Encryption,
+    pub chunks: Vec<ChunkEntry>,
+    pub erasure: Option<ErasureLayout>,
+    pub parity: Vec<ShardEntry>,
+}
+
+impl Manifest {
+    // Hashes of everything stored for this file, chunks and parity shards
+    pub fn stored_hashes(&self) -> Vec<&str> {
+        self.chunks
+            .iter()
+            .map(|chunk| chunk.hash.as_str())
+            .chain(self.parity.iter().map(|shard| shard.hash.as_str()))
+            .collect()
+    }
+
+    // Every (node, hash) pair a replica of a chunk or parity shard is stored at
+    pub fn replica_locations(&self) -> Vec<(&str, &str)> {
+        self.chunks
+            .iter()
+            .map(|chunk| (&chunk.hash, &chunk.nodes))
+            .chain(self.parity.iter().map(|shard| (&shard.hash, &shard.nodes)))
+            .flat_map(|(hash, nodes)| nodes.iter().map(move |node| (node.as_str(), hash.as_str())))
+            .collect()
+    }
+
+    pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS manifests (
+                fileName TEXT PRIMARY KEY,
+                size INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                replicationFactor INTEGER NOT NULL DEFAULT 1,
+                encryption INTEGER NOT NULL DEFAULT 0,
+                salt BLOB,
+                dataShards INTEGER NOT NULL DEFAULT 0,
+                parityShards INTEGER NOT NULL DEFAULT 0
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS file_chunks (
+                fileName TEXT NOT NULL REFERENCES manifests(fileName),
+                chunkIndex INTEGER NOT NULL,
+                chunkOffset INTEGER NOT NULL,
+                chunkSize INTEGER NOT NULL,
+                storedSize INTEGER NOT NULL,
+                chunkHash TEXT NOT NULL,
+                codec INTEGER NOT NULL,
+                PRIMARY KEY (fileName, chunkIndex)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS chunk_nodes (
+                fileName TEXT NOT NULL,
+                chunkIndex INTEGER NOT NULL,
+                node TEXT NOT NULL,
+                PRIMARY KEY (fileName, chunkIndex, node)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS parity_shards (
+                fileName TEXT NOT NULL REFERENCES manifests(fileName),
+                shardIndex INTEGER NOT NULL,
+                storedSize INTEGER NOT NULL,
+                shardHash TEXT NOT NULL,
+                PRIMARY KEY (fileName, shardIndex)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS parity_nodes (
+                fileName TEXT NOT NULL,
+                shardIndex INTEGER NOT NULL,
+                node TEXT NOT NULL,
+                PRIMARY KEY (fileName, shardIndex, node)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    // Replace whatever is stored under this manifest's file name
+    pub fn save(&self, conn: &Connection) -> rusqlite::Result<()> {
+        Manifest::delete(conn, &self.file_name)?;
+        let (data_shards, parity_shards) = self.erasure.map_or((0, 0), |layout| (layout.data_shards, layout.parity_shards));
+        conn.execute(
+            "INSERT INTO manifests (fileName, size, hash, replicationFactor, encryption, salt, dataShards, parityShards) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                self.file_name,
+                self.size as i64,
+                self.hash,
+                self.replication_factor as i64,
+                self.encryption.id(),
+                self.encryption.salt().map(|salt| &salt[..]),
+                data_shards as i64,
+                parity_shards as i64
+            ],
+        )?;
+
+        for (chunk_index, chunk) in self.chunks.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO file_chunks (fileName, chunkIndex, chunkOffset, chunkSize, storedSize, chunkHash, codec) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![self.file_name, chunk_index as i64, chunk.offset as i64, chunk.size as i64, chunk.stored_size as i64, chunk.hash, chunk.codec],
+            )?;
+            for node in &chunk.nodes {
+                conn.execute(
+                    "INSERT OR IGNORE INTO chunk_nodes (fileName, chunkIndex, node) VALUES (?1, ?2, ?3)",
+                    params![self.file_name, chunk_index as i64, node],
+                )?;
+            }
+        }
+
+        for (shard_index, shard) in self.parity.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO parity_shards (fileName, shardIndex, storedSize, shardHash) VALUES (?1, ?2, ?3, ?4)",
+                params![self.file_name, shard_index as i64, shard.stored_size as i64, shard.hash],
+            )?;
+            for node in &shard.nodes {
+                conn.execute(
+                    "INSERT OR IGNORE INTO parity_nodes (fileName, shardIndex, node) VALUES (?1, ?2, ?3)",
+                    params![self.file_name, shard_index as i64, node],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete(conn: &Connection, file_name: &str) -> rusqlite::Result<()> {
+        conn.execute("DELETE FROM parity_nodes WHERE fileName = ?1", params![file_name])?;
+        conn.execute("DELETE FROM parity_shards WHERE fileName = ?1", params![file_name])?;
+        conn.execute("DELETE FROM chunk_nodes WHERE fileName = ?1", params![file_name])?;
+        conn.execute("DELETE FROM file_chunks WHERE fileName = ?1", params![file_name])?;
+        conn.execute("DELETE FROM manifests WHERE fileName = ?1", params![file_name])?;
+        Ok(())
+    }
+
+    pub fn load(conn: &Connection, file_name: &str) -> rusqlite::Result<Option<Manifest>> {
+        type Header = (i64, String, i64, u8, Option<Vec<u8>>, i64, i64);
+        let header: Option<Header> = conn
+            .query_row(
+                "SELECT size, hash, replicationFactor, encryption, salt, dataShards, parityShards FROM manifests WHERE fileName = ?1",
+                params![file_name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?)),
+            )
+            .optional()?;
+        let (size, hash, replication_factor, encryption_id, salt, data_shards, parity_shards) = match header {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let encryption = Encryption::from_parts(encryption_id, salt.as_deref())
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Integer, e.into()))?;
+
+        let mut nodes: BTreeMap<i64, Vec<String>> = BTreeMap::new();
+        let mut stmt = conn.prepare("SELECT chunkIndex, node FROM chunk_nodes WHERE fileName = ?1 ORDER BY chunkIndex, node")?;
+        for row in stmt.query_map(params![file_name], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            let (chunk_index, node) = row?;
+            nodes.entry(chunk_index).or_default().push(node);
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT chunkIndex, chunkOffset, chunkSize, storedSize, chunkHash, codec FROM file_chunks WHERE fileName = ?1 ORDER BY chunkIndex"
+        )?;
+        let chunks = stmt
+            .query_map(params![file_name], |row| {
+                let chunk_index: i64 = row.get(0)?;
+                Ok(ChunkEntry {
+                    offset: row.get::<_, i64>(1)? as u64,
+                    size: row.get::<_, i64>(2)? as u64,
+                    stored_size: row.get::<_, i64>(3)? as u64,
+                    hash: row.get(4)?,
+                    codec: row.get(5)?,
+                    nodes: nodes.remove(&chunk_index).unwrap_or_default(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ChunkEntry>>>()?;
+
+        let mut parity_nodes: BTreeMap<i64, Vec<String>> = BTreeMap::new();
+        let mut stmt = conn.prepare("SELECT shardIndex, node FROM parity_nodes WHERE fileName = ?1 ORDER BY shardIndex, node")?;
+        for row in stmt.query_map(params![file_name], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            let (shard_index, node) = row?;
+            parity_nodes.entry(shard_index).or_default().push(node);
+        }
+
+        let mut stmt = conn.prepare("SELECT shardIndex, storedSize, shardHash FROM parity_shards WHERE fileName = ?1 ORDER BY shardIndex")?;
+        let parity = stmt
+            .query_map(params![file_name], |row| {
+                let shard_index: i64 = row.get(0)?;
+                Ok(ShardEntry {
+                    stored_size: row.get::<_, i64>(1)? as u64,
+                    hash: row.get(2)?,
+                    nodes: parity_nodes.remove(&shard_index).unwrap_or_default(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ShardEntry>>>()?;
+
+        let erasure = (data_shards > 0).then_some(ErasureLayout {
+            data_shards: data_shards as usize,
+            parity_shards: parity_shards as usize,
+        });
+
+        Ok(Some(Manifest {
+            file_name: file_name.to_string(),
+            size: size as u64,
+            hash,
+            replication_factor: replication_factor as usize,
+            encryption,
+            chunks,
+            erasure,
+            parity,
+        }))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MANIFEST_MAGIC);
+        bytes.push(MANIFEST_VERSION);
+        write_string(&mut bytes, &self.file_name, WHAT)?;
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&hash_to_bytes(&self.hash)?);
+        bytes.push(u8::try_from(self.replication_factor).map_err(|_| "Replication factor is over 255")?);
+        bytes.push(self.encryption.id());
+        if let Some(salt) = self.encryption.salt() {
+            bytes.extend_from_slice(salt);
+        }
+        bytes.extend_from_slice(&(self.chunks.len() as u32).to_be_bytes());
+
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&chunk.offset.to_be_bytes());
+            bytes.extend_from_slice(&chunk.size.to_be_bytes());
+            bytes.extend_from_slice(&chunk.stored_size.to_be_bytes());
+            bytes.extend_from_slice(&hash_to_bytes(&chunk.hash)?);
+            bytes.push(chunk.codec as u8);
+            write_nodes(&mut bytes, &chunk.nodes)?;
+        }
+
+        let (data_shards, parity_shards) = self.erasure.map_or((0, 0), |layout| (layout.data_shards, layout.parity_shards));
+        bytes.push(u8::try_from(data_shards).map_err(|_| "Too many data shards")?);
+        bytes.push(u8::try_from(parity_shards).map_err(|_| "Too many parity shards")?);
+        bytes.extend_from_slice(&(self.parity.len() as u32).to_be_bytes());
+        for shard in &self.parity {
+            bytes.extend_from_slice(&shard.stored_size.to_be_bytes());
+            bytes.extend_from_slice(&hash_to_bytes(&shard.hash)?);
+            write_nodes(&mut bytes, &shard.nodes)?;
+        }
+
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        Ok(bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Manifest, String> {
+        if bytes.len() < MANIFEST_MAGIC.len() + 1 + 4 {
+            return Err("Manifest is too short".into());
+        }
+        if &bytes[..4] != MANIFEST_MAGIC {
+            return Err("Not a manifest".into());
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32(body) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+            return Err("Manifest checksum mismatch".into());
+        }
+
+        let mut reader = ByteReader::new(&body[MANIFEST_MAGIC.len()..], WHAT);
+        let version = reader.u8()?;
+        if version != MANIFEST_VERSION {
+            return Err(format!("Unsupported manifest version {}", version));
+        }
+
+        let file_name = reader.string()?;
+        let size = reader.u64()?;
+        let hash = read_hash(&mut reader)?;
+        let replication_factor = reader.u8()? as usize;
+        let encryption_id = reader.u8()?;
+        let salt = if encryption_id == Encryption::PASSPHRASE_ID {
+            Some(reader.take(SALT_LEN)?)
+        } else {
+            None
+        };
+        let encryption = Encryption::from_parts(encryption_id, salt)?;
+        let chunk_count = reader.u32()? as usize;
+
+        let mut chunks = Vec::new();
+        for _ in 0..chunk_count {
+            let offset = reader.u64()?;
+            let chunk_size = reader.u64()?;
+            let stored_size = reader.u64()?;
+            let chunk_hash = read_hash(&mut reader)?;
+            let codec_id = reader.u8()?;
+            let codec = CodecId::from_u8(codec_id).ok_or_else(|| format!("Unknown codec {}", codec_id))?;
+            let nodes = read_nodes(&mut reader)?;
+            chunks.push(ChunkEntry { offset, size: chunk_size, stored_size, hash: chunk_hash, codec, nodes });
+        }
+
+        let data_shards = reader.u8()? as usize;
+        let parity_shards = reader.u8()? as usize;
+        let erasure = (data_shards > 0).then_some(ErasureLayout { data_shards, parity_shards });
+        let parity_count = reader.u32()? as usize;
+        let mut parity = Vec::new();
+        for _ in 0..parity_count {
+            let stored_size = reader.u64()?;
+            let shard_hash = read_hash(&mut reader)?;
+            let nodes = read_nodes(&mut reader)?;
+            parity.push(ShardEntry { stored_size, hash: shard_hash, nodes });
+        }
+
+        if !reader.is_empty() {
+            return Err("Manifest has trailing bytes".into());
+        }
+        Ok(Manifest { file_name, size, hash, replication_factor, encryption, chunks, erasure, parity })
+    }
+}
+
+fn write_nodes(bytes: &mut Vec<u8>, nodes: &[String]) -> Result<(), String> {
+    let node_count = u8::try_from(nodes.len()).map_err(|_| "A chunk can list at most 255 nodes")?;
+    bytes.push(node_count);
+    for node in nodes {
+        write_string(bytes, node, WHAT)?;
+    }
+    Ok(())
+}
+
+fn hash_to_bytes(hash: &str) -> Result<[u8; HASH_LEN], String> {
+    if hash.len() != HASH_LEN * 2 {
+        return Err(format!("'{}' is not a SHA-256 hash", hash));
+    }
+    let mut bytes = [0u8; HASH_LEN];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16).map_err(|_| format!("'{}' is not a SHA-256 hash", hash))?;
+    }
+    Ok(bytes)
+}
+
+fn read_nodes(reader: &mut ByteReader) -> Result<Vec<String>, String> {
+    let node_count = reader.u8()?;
+    (0..node_count).map(|_| reader.string()).collect()
+}
+
+fn read_hash(reader: &mut ByteReader) -> Result<String, String> {
+    Ok(reader.take(HASH_LEN)?.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(seed: u8) -> String {
+        format!("{:02x}", seed).repeat(HASH_LEN)
+    }
+
+    fn sample() -> Manifest {
+        let chunk = |index: u64, codec: CodecId, nodes: &[&str]| ChunkEntry {
+            offset: index * 1000,
+            size: 1000,
+            stored_size: 600 + index,
+            hash: hash(index as u8),
+            codec,
+            nodes: nodes.iter().map(|node| node.to_string()).collect(),
+        };
+        Manifest {
+            file_name: "report.pdf".into(),
+            size: 2500,
+            hash: hash(0xAB),
+            replication_factor: 2,
+            encryption: Encryption::Passphrase { salt: [7; SALT_LEN] },
+            chunks: vec![
+                chunk(0, CodecId::Huffman, &["10.0.0.1", "10.0.0.2"]),
+                chunk(1, CodecId::Lz77, &["10.0.0.2", "10.0.0.3"]),
+                chunk(2, CodecId::Stored, &[]),
+            ],
+            erasure: Some(ErasureLayout::DEFAULT),
+            parity: vec![ShardEntry { stored_size: 602, hash: hash(0xEE), nodes: vec!["10.0.0.4".into()] }],
+        }
+    }
+
+    // Recompute the checksum after editing the body, so parse gets past it
+    fn reseal(mut bytes: Vec<u8>) -> Vec<u8> {
+        bytes.truncate(bytes.len() - 4);
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let manifest = sample();
+        assert_eq!(Manifest::parse(&manifest.to_bytes().unwrap()).unwrap(), manifest);
+
+        let plain = Manifest { encryption: Encryption::None, erasure: None, parity: Vec::new(), chunks: Vec::new(), size: 0, ..sample() };
+        assert_eq!(Manifest::parse(&plain.to_bytes().unwrap()).unwrap(), plain);
+    }
+
+    #[test]
+    fn rejects_corrupt_bytes() {
+        let bytes = sample().to_bytes().unwrap();
+
+        for position in [4, 10, bytes.len() / 2, bytes.len() - 1] {
+            let mut flipped = bytes.clone();
+            flipped[position] ^= 0x01;
+            assert_eq!(Manifest::parse(&flipped).unwrap_err(), "Manifest checksum mismatch");
+        }
+        assert_eq!(Manifest::parse(&bytes[..6]).unwrap_err(), "Manifest is too short");
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert_eq!(Manifest::parse(&reseal(wrong_magic)).unwrap_err(), "Not a manifest");
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = MANIFEST_VERSION + 1;
+        assert!(Manifest::parse(&reseal(wrong_version)).unwrap_err().contains("Unsupported manifest version"));
+
+        let mut cut_short = bytes[..bytes.len() - 20].to_vec();
+        cut_short.extend_from_slice(&[0; 4]);
+        assert_eq!(Manifest::parse(&reseal(cut_short)).unwrap_err(), "Manifest is cut short");
+
+        let mut trailing = bytes.clone();
+        trailing.insert(bytes.len() - 4, 0);
+        assert_eq!(Manifest::parse(&reseal(trailing)).unwrap_err(), "Manifest has trailing bytes");
+    }
+
+    #[test]
+    fn refuses_to_write_what_it_cant_read_back() {
+        let bad_hash = Manifest { hash: "not a hash".into(), ..sample() };
+        assert!(bad_hash.to_bytes().is_err());
+        let long_name = Manifest { file_name: "x".repeat(70_000), ..sample() };
+        assert!(long_name.to_bytes().is_err());
+    }
+
+    #[test]
+    fn saves_loads_and_deletes() {
+        let conn = Connection::open_in_memory().unwrap();
+        Manifest::create_tables(&conn).unwrap();
+        assert_eq!(Manifest::load(&conn, "report.pdf").unwrap(), None);
+
+        let manifest = sample();
+        manifest.save(&conn).unwrap();
+        assert_eq!(Manifest::load(&conn, "report.pdf").unwrap(), Some(manifest.clone()));
+
+        // Saving again replaces every row of the previous version
+        let mut smaller = manifest.clone();
+        smaller.chunks.truncate(1);
+        smaller.chunks[0].nodes = vec!["10.0.0.9".into()];
+        smaller.encryption = Encryption::Keyring;
+        smaller.erasure = None;
+        smaller.parity.clear();
+        smaller.save(&conn).unwrap();
+        assert_eq!(Manifest::load(&conn, "report.pdf").unwrap(), Some(smaller));
+        let node_rows: i64 = conn.query_row("SELECT COUNT(*) FROM chunk_nodes", [], |row| row.get(0)).unwrap();
+        assert_eq!(node_rows, 1);
+
+        Manifest::delete(&conn, "report.pdf").unwrap();
+        assert_eq!(Manifest::load(&conn, "report.pdf").unwrap(), None);
+        for table in ["manifests", "file_chunks", "chunk_nodes", "parity_shards", "parity_nodes"] {
+            let rows: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap();
+            assert_eq!(rows, 0, "{}", table);
+        }
+    }
+}
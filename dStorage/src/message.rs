@@ -0,0 +1,289 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::codec::CodecId;
+use crate::frame::Frame;
+use crate::wire::{write_string, ByteReader};
+
+// Messages exchanged between nodes, each sent as one frame. The frame type is the message
+// type below; strings in payloads are a u16 length then UTF-8, integers are big-endian.
+//
+//   Join          0x0F   (empty)
+//   Leave         0x07   (empty)
+//   UploadInit    0x03   file name, size (u64), chunk count (u32), codec (u8)
+//   UploadChunk   0x04   file name, offset (u64), data (rest of the payload)
+//   UploadCommit  0x05   file name
+//   Download      0x01   file name, offset (u64), length (u32)
+//   StoreChunk    0x08   file name, data (rest of the payload)
+//   FetchChunk    0x09   chunk hash
+//   ReleaseChunk  0x0A   file name, chunk hash
+//   Ack           0x80   data (rest of the payload, usually empty)
+//   Error         0x81   code (u16), message
+const JOIN: u8 = 0x0F;
+const LEAVE: u8 = 0x07;
+const UPLOAD_INIT: u8 = 0x03;
+const UPLOAD_CHUNK: u8 = 0x04;
+const UPLOAD_COMMIT: u8 = 0x05;
+const DOWNLOAD: u8 = 0x01;
+const STORE_CHUNK: u8 = 0x08;
+const FETCH_CHUNK: u8 = 0x09;
+const RELEASE_CHUNK: u8 = 0x0A;
+const ACK: u8 = 0x80;
+const ERROR: u8 = 0x81;
+// Name of a message in errors of the wire helpers
+const WHAT: &str = "Message";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    BadRequest = 1,
+    NotInNetwork = 2,
+    NotFound = 3,
+    NotReady = 4,
+    Internal = 5,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 5] = [
+        ErrorCode::BadRequest,
+        ErrorCode::NotInNetwork,
+        ErrorCode::NotFound,
+        ErrorCode::NotReady,
+        ErrorCode::Internal,
+    ];
+
+    pub fn from_u16(code: u16) -> Option<ErrorCode> {
+        ErrorCode::ALL.into_iter().find(|error_code| *error_code as u16 == code)
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::BadRequest => "bad request",
+            ErrorCode::NotInNetwork => "not part of network",
+            ErrorCode::NotFound => "not found",
+            ErrorCode::NotReady => "not ready",
+            ErrorCode::Internal => "internal error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Join,
+    Leave,
+    UploadInit { file_name: String, size: u64, chunk_count: u32, codec: CodecId },
+    UploadChunk { file_name: String, offset: u64, data: Vec<u8> },
+    UploadCommit { file_name: String },
+    Download { file_name: String, offset: u64, length: u32 },
+    // Replicas of chunks and parity shards, held on behalf of a file of the sender
+    StoreChunk { file_name: String, data: Vec<u8> },
+    FetchChunk { hash: String },
+    ReleaseChunk { file_name: String, hash: String },
+    Ack { data: Vec<u8> },
+    Error { code: ErrorCode, message: String },
+}
+
+impl Message {
+    pub fn ack() -> Message {
+        Message::Ack { data: Vec::new() }
+    }
+
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Message {
+        Message::Error { code, message: message.into() }
+    }
+
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Message::Join => JOIN,
+            Message::Leave => LEAVE,
+            Message::UploadInit { .. } => UPLOAD_INIT,
+            Message::UploadChunk { .. } => UPLOAD_CHUNK,
+            Message::UploadCommit { .. } => UPLOAD_COMMIT,
+            Message::Download { .. } => DOWNLOAD,
+            Message::StoreChunk { .. } => STORE_CHUNK,
+            Message::FetchChunk { .. } => FETCH_CHUNK,
+            Message::ReleaseChunk { .. } => RELEASE_CHUNK,
+            Message::Ack { .. } => ACK,
+            Message::Error { .. } => ERROR,
+        }
+    }
+
+    pub fn to_frame(&self) -> Result<Frame, String> {
+        let mut payload = Vec::new();
+        match self {
+            Message::Join | Message::Leave => {}
+            Message::UploadInit { file_name, size, chunk_count, codec } => {
+                write_string(&mut payload, file_name, WHAT)?;
+                payload.extend_from_slice(&size.to_be_bytes());
+                payload.extend_from_slice(&chunk_count.to_be_bytes());
+                payload.push(*codec as u8);
+            }
+            Message::UploadChunk { file_name, offset, data } => {
+                write_string(&mut payload, file_name, WHAT)?;
+                payload.extend_from_slice(&offset.to_be_bytes());
+                payload.extend_from_slice(data);
+            }
+            Message::UploadCommit { file_name } => write_string(&mut payload, file_name, WHAT)?,
+            Message::Download { file_name, offset, length } => {
+                write_string(&mut payload, file_name, WHAT)?;
+                payload.extend_from_slice(&offset.to_be_bytes());
+                payload.extend_from_slice(&length.to_be_bytes());
+            }
+            Message::StoreChunk { file_name, data } => {
+                write_string(&mut payload, file_name, WHAT)?;
+                payload.extend_from_slice(data);
+            }
+            Message::FetchChunk { hash } => write_string(&mut payload, hash, WHAT)?,
+            Message::ReleaseChunk { file_name, hash } => {
+                write_string(&mut payload, file_name, WHAT)?;
+                write_string(&mut payload, hash, WHAT)?;
+            }
+            Message::Ack { data } => payload.extend_from_slice(data),
+            Message::Error { code, message } => {
+                payload.extend_from_slice(&(*code as u16).to_be_bytes());
+                write_string(&mut payload, message, WHAT)?;
+            }
+        }
+        Ok(Frame::new(self.message_type(), payload))
+    }
+
+    pub fn from_frame(frame: &Frame) -> Result<Message, String> {
+        let mut reader = ByteReader::new(&frame.payload, WHAT);
+        let message = match frame.message_type {
+            JOIN => Message::Join,
+            LEAVE => Message::Leave,
+            UPLOAD_INIT => Message::UploadInit {
+                file_name: reader.string()?,
+                size: reader.u64()?,
+                chunk_count: reader.u32()?,
+                codec: {
+                    let id = reader.u8()?;
+                    CodecId::from_u8(id).ok_or_else(|| format!("Unknown codec {}", id))?
+                },
+            },
+            UPLOAD_CHUNK => Message::UploadChunk {
+                file_name: reader.string()?,
+                offset: reader.u64()?,
+                data: reader.rest(),
+            },
+            UPLOAD_COMMIT => Message::UploadCommit { file_name: reader.string()? },
+            DOWNLOAD => Message::Download {
+                file_name: reader.string()?,
+                offset: reader.u64()?,
+                length: reader.u32()?,
+            },
+            STORE_CHUNK => Message::StoreChunk { file_name: reader.string()?, data: reader.rest() },
+            FETCH_CHUNK => Message::FetchChunk { hash: reader.string()? },
+            RELEASE_CHUNK => Message::ReleaseChunk { file_name: reader.string()?, hash: reader.string()? },
+            ACK => Message::Ack { data: reader.rest() },
+            ERROR => {
+                let code = reader.u16()?;
+                Message::Error {
+                    code: ErrorCode::from_u16(code).ok_or_else(|| format!("Unknown error code {}", code))?,
+                    message: reader.string()?,
+                }
+            }
+            message_type => return Err(format!("Unknown message type {:#04x}", message_type)),
+        };
+        if !reader.is_empty() {
+            return Err("Message has trailing bytes".into());
+        }
+        Ok(message)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.to_frame()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .write_to(writer)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Message> {
+        let frame = Frame::read_from(reader)?;
+        Message::from_frame(&frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub async fn write_to_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        self.to_frame()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .write_to_async(writer)
+            .await
+    }
+
+    pub async fn read_from_async<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Message> {
+        let frame = Frame::read_from_async(reader).await?;
+        Message::from_frame(&frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_messages() -> Vec<Message> {
+        let mut messages = vec![
+            Message::Join,
+            Message::Leave,
+            Message::UploadInit { file_name: "report.pdf".into(), size: 12_345_678, chunk_count: 4, codec: CodecId::Lz77 },
+            Message::UploadInit { file_name: String::new(), size: 0, chunk_count: 0, codec: CodecId::Stored },
+            Message::UploadChunk { file_name: "report.pdf".into(), offset: 4 * 1024 * 1024, data: (0..=255).collect() },
+            Message::UploadChunk { file_name: "empty".into(), offset: 0, data: Vec::new() },
+            Message::UploadCommit { file_name: "report.pdf".into() },
+            Message::Download { file_name: "café/ünïcode.txt".into(), offset: 0, length: 1024 * 1024 },
+            Message::Download { file_name: "report.pdf".into(), offset: u64::MAX, length: u32::MAX },
+            Message::StoreChunk { file_name: "report.pdf".into(), data: vec![0, 1, 2, 3] },
+            Message::FetchChunk { hash: "ab".repeat(32) },
+            Message::ReleaseChunk { file_name: "report.pdf".into(), hash: "cd".repeat(32) },
+            Message::ack(),
+            Message::Ack { data: b"encoded text".to_vec() },
+        ];
+        for code in ErrorCode::ALL {
+            messages.push(Message::error(code, format!("Declined: {}", code)));
+        }
+        messages
+    }
+
+    #[test]
+    fn round_trips_through_frames() {
+        for message in all_messages() {
+            let frame = message.to_frame().unwrap();
+            assert_eq!(frame.message_type, message.message_type());
+            assert_eq!(Message::from_frame(&frame).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_a_stream() {
+        let mut wire = Vec::new();
+        for message in all_messages() {
+            message.write_to(&mut wire).unwrap();
+        }
+        let mut reader = wire.as_slice();
+        for message in all_messages() {
+            assert_eq!(Message::read_from(&mut reader).unwrap(), message);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn message_types_are_distinct() {
+        let mut types: Vec<u8> = all_messages().iter().map(Message::message_type).collect();
+        types.sort();
+        types.dedup();
+        assert_eq!(types.len(), 11);
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        assert!(Message::from_frame(&Frame::new(0x42, Vec::new())).is_err());
+        assert!(Message::from_frame(&Frame::new(JOIN, vec![0])).is_err());
+        assert!(Message::from_frame(&Frame::new(DOWNLOAD, vec![0, 5, b'a'])).is_err());
+        assert!(Message::from_frame(&Frame::new(DOWNLOAD, vec![0, 1, b'a', 0, 0, 0, 0])).is_err());
+        assert!(Message::from_frame(&Frame::new(UPLOAD_INIT, vec![0, 0, 1, 2])).is_err());
+        assert!(Message::from_frame(&Frame::new(ERROR, vec![0, 99, 0, 0])).is_err());
+        assert!(Message::from_frame(&Frame::new(DOWNLOAD, vec![0, 2, 0xff, 0xfe])).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use std::cmp::Reverse;
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+
+use crate::manifest::Manifest;
+use crate::local_node;
+
+// Every chunk and parity shard of a file is stored on replication_factor distinct peers
+pub const DEFAULT_REPLICATION_FACTOR: usize = 3;
+
+// Rendezvous score of a peer for a chunk: the first 8 bytes of SHA-256(hash, peer)
+fn score(hash: &str, peer: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(hash.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(peer.as_bytes());
+    u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
+
+// Pick the peers that hold the replicas of a chunk, highest rendezvous score first. The
+// choice only depends on the chunk hash and the peer set, so chunks spread evenly over the
+// peers and adding or removing a peer only moves the chunks that peer holds.
+pub fn place_replicas(hash: &str, peers: &[String], replication_factor: usize) -> Result<Vec<String>, String> {
+    if replication_factor == 0 {
+        return Err("Replication factor must be at least 1".into());
+    }
+    let candidates = rank_peers(hash, peers);
+    if candidates.len() < replication_factor {
+        return Err(format!(
+            "A replication factor of {} needs {} distinct peers, only {} known",
+            replication_factor,
+            replication_factor,
+            candidates.len()
+        ));
+    }
+
+    Ok(candidates.into_iter().take(replication_factor).collect())
+}
+
+// Every distinct peer in rendezvous order for a chunk. The first replication_factor are
+// where place_replicas puts it, the rest are the fallbacks when one of those can't take it.
+pub fn rank_peers(hash: &str, peers: &[String]) -> Vec<String> {
+    let mut candidates: Vec<&String> = peers.iter().collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates.sort_by_key(|peer| Reverse(score(hash, peer)));
+    candidates.into_iter().cloned().collect()
+}
+
+pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS peers (
+            ip TEXT PRIMARY KEY,
+            lastSeen INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// Record a peer that joined the network, or refresh when it was last seen
+pub fn add_peer(conn: &Connection, ip: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO peers (ip, lastSeen) VALUES (?1, ?2) ON CONFLICT(ip) DO UPDATE SET lastSeen = excluded.lastSeen",
+        params![ip, unix_time()],
+    )?;
+    Ok(())
+}
+
+// Every peer replicas can be placed on, this node included
+pub fn known_peers(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT ip FROM peers ORDER BY ip")?;
+    let mut peers = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    if !peers.iter().any(|peer| peer == local_node()) {
+        peers.insert(0, local_node().to_string());
+    }
+    Ok(peers)
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+// Every node sends a heartbeat (a repeated join request) to the peers it knows this often
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+// A peer that hasn't sent a heartbeat for this long is suspect: it stays in the manifests,
+// but its replicas no longer count and copies are made on live peers
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// A peer that hasn't sent a heartbeat for this long is gone and dropped from the manifests
+pub const PEER_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// A peer left with a leave request
+pub fn remove_peer(conn: &Connection, ip: &str) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM peers WHERE ip = ?1", params![ip])?;
+    Ok(())
+}
+
+// Peers heard from within the timeout, this node included
+pub fn live_peers(conn: &Connection, timeout: Duration) -> rusqlite::Result<Vec<String>> {
+    let cutoff = unix_time() - timeout.as_secs() as i64;
+    let mut stmt = conn.prepare("SELECT ip FROM peers WHERE lastSeen >= ?1 ORDER BY ip")?;
+    let mut peers = stmt
+        .query_map(params![cutoff], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    if !peers.iter().any(|peer| peer == local_node()) {
+        peers.insert(0, local_node().to_string());
+    }
+    Ok(peers)
+}
+
+// Peers last heard from after the expiry but before the timeout
+pub fn suspect_peers(conn: &Connection, timeout: Duration, expiry: Duration) -> rusqlite::Result<Vec<String>> {
+    let now = unix_time();
+    let mut stmt = conn.prepare("SELECT ip FROM peers WHERE lastSeen < ?1 AND lastSeen >= ?2 ORDER BY ip")?;
+    let peers = stmt
+        .query_map(params![now - timeout.as_secs() as i64, now - expiry.as_secs() as i64], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(peers)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RepairReport {
+    pub replicas_checked: usize,
+    pub replicas_copied: usize,
+    pub under_replicated: usize,
+    pub lost: usize,
+}
+
+// Bring every chunk and parity shard listed in pointers.db back to its file's replication
+// factor of live holders. Suspect holders stay in the manifest in case they come back, but
+// only holders that are neither live nor suspect are dropped. The missing copies are made with
+// copy(file name, from, to, hash) onto live peers in rendezvous order. Anything left without
+// a single live or suspect holder is counted as lost, erasure coding may still rebuild it.
+pub fn repair<F>(conn: &Connection, live: &[String], suspect: &[String], mut copy: F) -> Result<RepairReport, Box<dyn Error>>
+where
+    F: FnMut(&str, &str, &str, &str) -> Result<(), String>,
+{
+    let mut report = RepairReport::default();
+    let mut stmt = conn.prepare("SELECT fileName FROM manifests ORDER BY fileName")?;
+    let file_names = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    for file_name in file_names {
+        let mut manifest = match Manifest::load(conn, &file_name)? {
+            Some(manifest) => manifest,
+            None => continue,
+        };
+        let replication_factor = manifest.replication_factor;
+
+        let mut changed = false;
+        let replicas = manifest
+            .chunks
+            .iter_mut()
+            .map(|chunk| (&chunk.hash, &mut chunk.nodes))
+            .chain(manifest.parity.iter_mut().map(|shard| (&shard.hash, &mut shard.nodes)));
+        for (hash, nodes) in replicas {
+            report.replicas_checked += 1;
+            let live_holders: Vec<String> = nodes.iter().filter(|node| live.contains(node)).cloned().collect();
+            let suspect_holders: Vec<String> = nodes
+                .iter()
+                .filter(|node| suspect.contains(node) && !live.contains(node))
+                .cloned()
+                .collect();
+            if live_holders.len() + suspect_holders.len() != nodes.len() {
+                changed = true;
+            }
+            if live_holders.is_empty() && suspect_holders.is_empty() {
+                eprintln!("{} of {} has no live holder left", hash, file_name);
+                report.lost += 1;
+                nodes.clear();
+                continue;
+            }
+
+            // Copy from the live holders first, a suspect one is only tried when none answers
+            let sources: Vec<&String> = live_holders.iter().chain(&suspect_holders).collect();
+            let mut new_nodes = live_holders.clone();
+            let mut live_count = live_holders.len();
+            let targets = place_replicas(hash, live, live.len())?;
+            for target in targets.iter().filter(|target| !live_holders.contains(target)) {
+                if live_count >= replication_factor {
+                    break;
+                }
+                let copied = sources.iter().any(|holder| match copy(&file_name, holder, target, hash) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("Unable to copy {} from {} to {}: {}", hash, holder, target, e);
+                        false
+                    }
+                });
+                if copied {
+                    println!("Copied {} of {} to {}", hash, file_name, target);
+                    new_nodes.push(target.clone());
+                    live_count += 1;
+                    report.replicas_copied += 1;
+                    changed = true;
+                }
+            }
+            if live_count < replication_factor {
+                report.under_replicated += 1;
+            }
+            new_nodes.extend(suspect_holders);
+            *nodes = new_nodes;
+        }
+
+        if changed {
+            let transaction = conn.unchecked_transaction()?;
+            manifest.save(&transaction)?;
+            transaction.commit()?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecId;
+    use crate::encryption::Encryption;
+    use crate::manifest::ChunkEntry;
+
+    fn peers(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn hash(i: usize) -> String {
+        crate::chunk_store::sha256_hex(&i.to_be_bytes())
+    }
+
+    fn manifest(chunk_nodes: &[&[&str]]) -> Manifest {
+        let chunks = chunk_nodes
+            .iter()
+            .enumerate()
+            .map(|(i, nodes)| ChunkEntry {
+                offset: i as u64 * 10,
+                size: 10,
+                stored_size: 10,
+                hash: hash(i),
+                codec: CodecId::Stored,
+                nodes: peers(nodes),
+            })
+            .collect();
+        Manifest {
+            file_name: "report.txt".into(),
+            size: chunk_nodes.len() as u64 * 10,
+            hash: hash(1000),
+            replication_factor: 3,
+            encryption: Encryption::None,
+            chunks,
+            erasure: None,
+            parity: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ranks_peers_the_same_whatever_their_order() {
+        let all = peers(&["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4", "10.0.0.5"]);
+        let shuffled = peers(&["10.0.0.4", "10.0.0.2", "10.0.0.5", "10.0.0.2", "10.0.0.1", "10.0.0.3"]);
+        for i in 0..50 {
+            let ranked = rank_peers(&hash(i), &all);
+            assert_eq!(ranked.len(), all.len());
+            assert_eq!(rank_peers(&hash(i), &shuffled), ranked);
+            assert_eq!(place_replicas(&hash(i), &shuffled, 3).unwrap(), ranked[..3]);
+        }
+
+        // Different chunks land on different peers
+        let firsts: Vec<String> = (0..50).map(|i| rank_peers(&hash(i), &all).remove(0)).collect();
+        assert!(all.iter().all(|peer| firsts.contains(peer)));
+
+        assert!(place_replicas(&hash(0), &all[..2], 3).is_err());
+        assert!(place_replicas(&hash(0), &all, 0).is_err());
+    }
+
+    #[test]
+    fn a_leaving_peer_only_moves_its_own_replicas() {
+        let all = peers(&["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4", "10.0.0.5", "10.0.0.6"]);
+        let remaining: Vec<String> = all.iter().filter(|peer| *peer != "10.0.0.3").cloned().collect();
+        let mut moved = 0;
+        for i in 0..200 {
+            let before = place_replicas(&hash(i), &all, 3).unwrap();
+            let after = place_replicas(&hash(i), &remaining, 3).unwrap();
+            if before.iter().any(|peer| peer == "10.0.0.3") {
+                // The other holders keep their replica, one new peer takes over
+                assert!(before.iter().filter(|peer| *peer != "10.0.0.3").all(|peer| after.contains(peer)));
+                moved += 1;
+            } else {
+                assert_eq!(after, before);
+            }
+        }
+        assert!(moved > 0 && moved < 200);
+    }
+
+    #[test]
+    fn sorts_peers_by_their_last_heartbeat() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        for peer in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            add_peer(&conn, peer).unwrap();
+        }
+        let ago = |peer: &str, seconds: i64| {
+            conn.execute("UPDATE peers SET lastSeen = ?1 WHERE ip = ?2", params![unix_time() - seconds, peer]).unwrap();
+        };
+        ago("10.0.0.2", 10 * 60);
+        ago("10.0.0.3", 8 * 24 * 60 * 60);
+
+        let live = live_peers(&conn, HEARTBEAT_TIMEOUT).unwrap();
+        assert!(live.contains(&"10.0.0.1".to_string()) && live.contains(&local_node().to_string()));
+        assert_eq!(live.len(), 2);
+        assert_eq!(suspect_peers(&conn, HEARTBEAT_TIMEOUT, PEER_EXPIRY).unwrap(), peers(&["10.0.0.2"]));
+        assert_eq!(known_peers(&conn).unwrap().len(), 4);
+
+        // A heartbeat brings a suspect peer back, a leave request drops it for good
+        add_peer(&conn, "10.0.0.2").unwrap();
+        assert_eq!(live_peers(&conn, HEARTBEAT_TIMEOUT).unwrap().len(), 3);
+        assert!(suspect_peers(&conn, HEARTBEAT_TIMEOUT, PEER_EXPIRY).unwrap().is_empty());
+        remove_peer(&conn, "10.0.0.2").unwrap();
+        assert_eq!(known_peers(&conn).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn repair_copies_replicas_onto_live_peers() {
+        let conn = Connection::open_in_memory().unwrap();
+        Manifest::create_tables(&conn).unwrap();
+        manifest(&[
+            &["10.0.0.1", "10.0.0.2", "10.0.0.3"],
+            &["10.0.0.1", "10.0.0.8", "10.0.0.9"],
+            &["10.0.0.9"],
+        ])
+        .save(&conn)
+        .unwrap();
+        let live = peers(&["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4", "10.0.0.5"]);
+        let suspect = peers(&["10.0.0.8"]);
+
+        // The first peer in line for the copies of chunk 1 is down, the next ones take them
+        let targets: Vec<String> = rank_peers(&hash(1), &live).into_iter().filter(|peer| peer != "10.0.0.1").collect();
+        let mut copies = Vec::new();
+        let report = repair(&conn, &live, &suspect, |file_name, from, to, hash| {
+            assert_eq!((file_name, hash), ("report.txt", self::hash(1).as_str()));
+            if to == targets[0] {
+                return Err("Connection refused".into());
+            }
+            copies.push((from.to_string(), to.to_string()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(report, RepairReport { replicas_checked: 3, replicas_copied: 2, under_replicated: 0, lost: 1 });
+        // Copies come from the live holder, the suspect one is only a fallback
+        assert_eq!(copies, targets[1..3].iter().map(|target| ("10.0.0.1".to_string(), target.clone())).collect::<Vec<_>>());
+
+        // The expired holder is dropped, the suspect one stays on top of three live ones
+        let repaired = Manifest::load(&conn, "report.txt").unwrap().unwrap();
+        assert_eq!(repaired.chunks[0].nodes, peers(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]));
+        assert_eq!(repaired.chunks[1].nodes, [&["10.0.0.1".to_string()], &targets[1..3], &["10.0.0.8".to_string()]].concat());
+        assert!(repaired.chunks[2].nodes.is_empty());
+
+        // Nothing left to do on the next pass
+        let report = repair(&conn, &live, &suspect, |_, _, _, _| panic!("Nothing should be copied")).unwrap();
+        assert_eq!(report.replicas_copied, 0);
+    }
+}
@@ -0,0 +1,267 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::message::{ErrorCode, Message};
+
+// Accept loop for node connections on tokio. Every connection is a task that reads one
+// message at a time and writes back the reply of the handler. Handlers talk to SQLite and
+// the disk, so they run on the blocking pool; they only see the Peer and the Message and
+// return the reply, which keeps them testable without a socket.
+//
+// At most max_connections are served at once, further peers wait in the listen backlog
+// until a slot frees up. A connection that doesn't deliver its next message within the
+// idle timeout is closed, so a peer that goes quiet can't hold a slot forever. Once the
+// shutdown future completes no new connections are accepted, idle connections are closed
+// and requests being handled are still answered before run returns.
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+// How long a connection may take to send its next message before it is closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Pause after a failed accept, so running out of file descriptors doesn't spin the loop
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub ip: String,
+    pub local_ip: String,
+}
+
+pub type Handler = Arc<dyn Fn(&Peer, Message) -> Message + Send + Sync>;
+
+pub struct Server {
+    listener: TcpListener,
+    max_connections: usize,
+    idle_timeout: Duration,
+    handler: Handler,
+}
+
+impl Server {
+    pub async fn bind<A: ToSocketAddrs>(addrs: A, max_connections: usize, handler: Handler) -> io::Result<Server> {
+        if max_connections == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "A server needs room for at least one connection"));
+        }
+        let listener = TcpListener::bind(addrs).await?;
+        Ok(Server { listener, max_connections, idle_timeout: DEFAULT_IDLE_TIMEOUT, handler })
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Server {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn run<S: Future<Output = ()>>(self, shutdown: S) -> io::Result<()> {
+        let slots = Arc::new(Semaphore::new(self.max_connections));
+        let (stop, stopped) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            let slot = tokio::select! {
+                slot = slots.clone().acquire_owned() => slot.expect("Connection slots are never closed"),
+                _ = &mut shutdown => break,
+            };
+            let (stream, addr) = tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+
+            let handler = self.handler.clone();
+            let stopped = stopped.clone();
+            let idle_timeout = self.idle_timeout;
+            connections.spawn(async move {
+                if let Err(e) = serve_connection(stream, handler, idle_timeout, stopped).await {
+                    eprintln!("Connection with {} failed: {}", addr, e);
+                }
+                drop(slot);
+            });
+            while connections.try_join_next().is_some() {}
+        }
+
+        println!("Shutting down, waiting for {} connections", connections.len());
+        let _ = stop.send(true);
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+}
+
+// Serve the messages a peer sends on one connection until it hangs up, goes idle or the
+// server stops
+async fn serve_connection(mut stream: TcpStream, handler: Handler, idle_timeout: Duration, mut stopped: watch::Receiver<bool>) -> io::Result<()> {
+    let peer = Peer {
+        ip: stream.peer_addr()?.ip().to_string(),
+        local_ip: stream.local_addr()?.ip().to_string(),
+    };
+
+    loop {
+        // A message that is only half received when the server stops is dropped
+        let request = tokio::select! {
+            request = tokio::time::timeout(idle_timeout, Message::read_from_async(&mut stream)) => match request {
+                Ok(Ok(request)) => request,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    println!("Closing connection with {}, idle for {:?}", peer.ip, idle_timeout);
+                    return Ok(());
+                }
+            },
+            _ = stopped.wait_for(|stop| *stop) => return Ok(()),
+        };
+
+        let (handler, request_peer) = (handler.clone(), peer.clone());
+        let reply = tokio::task::spawn_blocking(move || handler(&request_peer, request))
+            .await
+            .unwrap_or_else(|e| Message::error(ErrorCode::Internal, format!("Request handler failed: {}", e)));
+        reply.write_to_async(&mut stream).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    fn echo() -> Handler {
+        Arc::new(|peer: &Peer, request: Message| match request {
+            Message::Download { file_name, .. } => Message::Ack { data: format!("{} from {}", file_name, peer.ip).into_bytes() },
+            _ => Message::error(ErrorCode::BadRequest, "Expected a download"),
+        })
+    }
+
+    fn download(file_name: &str) -> Message {
+        Message::Download { file_name: file_name.into(), offset: 0, length: 0 }
+    }
+
+    async fn exchange(stream: &mut TcpStream, request: &Message) -> io::Result<Message> {
+        request.write_to_async(stream).await?;
+        Message::read_from_async(stream).await
+    }
+
+    async fn start(max_connections: usize, handler: Handler) -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<io::Result<()>>) {
+        start_server(Server::bind("127.0.0.1:0", max_connections, handler).await.unwrap())
+    }
+
+    fn start_server(server: Server) -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<io::Result<()>>) {
+        let addr = server.local_addr().unwrap();
+        let (shutdown, shutdown_signal) = oneshot::channel();
+        let running = tokio::spawn(server.run(async move {
+            let _ = shutdown_signal.await;
+        }));
+        (addr, shutdown, running)
+    }
+
+    #[tokio::test]
+    async fn answers_every_message_on_a_connection() {
+        let (addr, shutdown, running) = start(4, echo()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for file_name in ["a", "b", "c"] {
+            let reply = exchange(&mut stream, &download(file_name)).await.unwrap();
+            assert_eq!(reply, Message::Ack { data: format!("{} from 127.0.0.1", file_name).into_bytes() });
+        }
+        assert!(matches!(exchange(&mut stream, &Message::Join).await.unwrap(), Message::Error { code: ErrorCode::BadRequest, .. }));
+
+        shutdown.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_connections() {
+        let (addr, shutdown, running) = start(1, echo()).await;
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        exchange(&mut first, &download("first")).await.unwrap();
+
+        // The second connection sits in the backlog until the first one hangs up
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(200), exchange(&mut second, &download("second"))).await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        let reply = tokio::time::timeout(Duration::from_secs(5), Message::read_from_async(&mut second)).await.unwrap().unwrap();
+        assert_eq!(reply, Message::Ack { data: b"second from 127.0.0.1".to_vec() });
+
+        shutdown.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections_to_free_their_slot() {
+        let server = Server::bind("127.0.0.1:0", 1, echo()).await.unwrap().with_idle_timeout(Duration::from_millis(200));
+        let (addr, shutdown, running) = start_server(server);
+
+        // Connected but silent, then stuck halfway through a frame
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut frame = Vec::new();
+        download("second").write_to_async(&mut frame).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut second, &frame[..frame.len() / 2]).await.unwrap();
+
+        let closed = tokio::time::timeout(Duration::from_secs(5), Message::read_from_async(&mut idle)).await.unwrap().unwrap_err();
+        assert_eq!(closed.kind(), io::ErrorKind::UnexpectedEof);
+        let closed = tokio::time::timeout(Duration::from_secs(5), Message::read_from_async(&mut second)).await.unwrap().unwrap_err();
+        assert_eq!(closed.kind(), io::ErrorKind::UnexpectedEof);
+
+        // The slot is free again for the next peer
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(5), exchange(&mut third, &download("third"))).await.unwrap().unwrap();
+        assert_eq!(reply, Message::Ack { data: b"third from 127.0.0.1".to_vec() });
+
+        shutdown.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn shuts_down_after_answering_requests_in_flight() {
+        let (entered, handler_entered) = std::sync::mpsc::channel();
+        let slow: Handler = Arc::new(move |_: &Peer, request: Message| {
+            let _ = entered.send(());
+            std::thread::sleep(Duration::from_millis(200));
+            Message::Ack { data: format!("{:?}", request).into_bytes() }
+        });
+        let (addr, shutdown, running) = start(4, slow).await;
+
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        download("slow").write_to_async(&mut busy).await.unwrap();
+        tokio::task::spawn_blocking(move || handler_entered.recv().unwrap()).await.unwrap();
+
+        shutdown.send(()).unwrap();
+        let reply = Message::read_from_async(&mut busy).await.unwrap();
+        assert!(matches!(reply, Message::Ack { .. }));
+        tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap().unwrap();
+
+        // Both connections were closed and nothing listens any more
+        let closed = Message::read_from_async(&mut idle).await.unwrap_err();
+        assert_eq!(closed.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_answers_with_an_internal_error() {
+        let (addr, shutdown, running) = start(4, Arc::new(|_: &Peer, _: Message| -> Message { panic!("handler bug") })).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let reply = exchange(&mut stream, &Message::Join).await.unwrap();
+        assert!(matches!(reply, Message::Error { code: ErrorCode::Internal, .. }));
+
+        shutdown.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+}
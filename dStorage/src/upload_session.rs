@@ -0,0 +1,356 @@
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::codec::CodecId;
+
+// Uploads arrive as UploadInit, any number of UploadChunk messages and an UploadCommit. The
+// session lives in the connections table of the network, next to the stage of the peer, and
+// the chunks received so far are listed in upload_chunks<network id>. Chunk data is written
+// straight into a staging file, so an upload interrupted halfway resumes from the chunks
+// that already made it: sending the same UploadInit again answers with their offsets.
+
+pub const STAGE_IDLE: &str = "idle";
+pub const STAGE_UPLOADING: &str = "uploading";
+// Largest file a peer may announce, it is staged on this node's disk in full
+pub const MAX_UPLOAD_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+fn connections_table(network_id: &str) -> String {
+    format!("connections{}", network_id)
+}
+
+fn chunks_table(network_id: &str) -> String {
+    format!("upload_chunks{}", network_id)
+}
+
+pub fn create_tables(conn: &Connection, network_id: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                ip TEXT PRIMARY KEY,
+                stage TEXT NOT NULL,
+                fileName TEXT,
+                fileSize INTEGER,
+                chunkCount INTEGER,
+                codec INTEGER
+            )",
+            connections_table(network_id)
+        ),
+        [],
+    )?;
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                ip TEXT NOT NULL,
+                chunkOffset INTEGER NOT NULL,
+                chunkSize INTEGER NOT NULL,
+                PRIMARY KEY (ip, chunkOffset)
+            )",
+            chunks_table(network_id)
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+// File names end up in paths on the receiving node, so they can't leave its directories
+fn check_file_name(file_name: &str) -> Result<(), String> {
+    if file_name.is_empty() || file_name == "." || file_name == ".." || file_name.contains(['/', '\\', '\0']) {
+        return Err(format!("'{}' is not a valid file name", file_name));
+    }
+    Ok(())
+}
+
+// Offsets of the received chunks, sent back in the Ack to an UploadInit (u64 big-endian each)
+pub fn encode_offsets(offsets: &[u64]) -> Vec<u8> {
+    offsets.iter().flat_map(|offset| offset.to_be_bytes()).collect()
+}
+
+pub fn decode_offsets(bytes: &[u8]) -> Result<Vec<u64>, String> {
+    if !bytes.len().is_multiple_of(8) {
+        return Err("Offset list is not a whole number of u64s".into());
+    }
+    Ok(bytes.chunks(8).map(|offset| u64::from_be_bytes(offset.try_into().unwrap())).collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadSession {
+    pub network_id: String,
+    pub ip: String,
+    pub file_name: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    // Codec the file was encoded with by the uploader
+    pub codec: CodecId,
+    // Directory the chunks are staged under until the upload is committed
+    pub staging_root: PathBuf,
+}
+
+impl UploadSession {
+    // The upload in progress from ip, if any
+    pub fn load(conn: &Connection, staging_root: &Path, network_id: &str, ip: &str) -> rusqlite::Result<Option<UploadSession>> {
+        conn.query_row(
+            &format!(
+                "SELECT fileName, fileSize, chunkCount, codec FROM {} WHERE ip = ?1 AND stage = ?2",
+                connections_table(network_id)
+            ),
+            params![ip, STAGE_UPLOADING],
+            |row| {
+                Ok(UploadSession {
+                    network_id: network_id.to_string(),
+                    ip: ip.to_string(),
+                    file_name: row.get(0)?,
+                    size: row.get::<_, i64>(1)? as u64,
+                    chunk_count: row.get::<_, i64>(2)? as u32,
+                    codec: row.get(3)?,
+                    staging_root: staging_root.to_path_buf(),
+                })
+            },
+        )
+        .optional()
+    }
+
+    // Start an upload, or resume the one ip already has in progress for the same file.
+    // Anything else ip was uploading is dropped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn start(conn: &Connection, staging_root: &Path, network_id: &str, ip: &str, file_name: &str, size: u64, chunk_count: u32, codec: CodecId) -> Result<UploadSession, Box<dyn Error>> {
+        check_file_name(file_name)?;
+        if size > MAX_UPLOAD_SIZE {
+            return Err(format!("'{}' is {} bytes, uploads are limited to {}", file_name, size, MAX_UPLOAD_SIZE).into());
+        }
+        // Every chunk holds at least one byte
+        if (size == 0) != (chunk_count == 0) || chunk_count as u64 > size {
+            return Err(format!("'{}' can't be {} bytes in {} chunks", file_name, size, chunk_count).into());
+        }
+        let session = UploadSession {
+            network_id: network_id.to_string(),
+            ip: ip.to_string(),
+            file_name: file_name.to_string(),
+            size,
+            chunk_count,
+            codec,
+            staging_root: staging_root.to_path_buf(),
+        };
+
+        match UploadSession::load(conn, staging_root, network_id, ip)? {
+            Some(existing) if existing == session => return Ok(existing),
+            Some(existing) => existing.discard(conn)?,
+            None => {}
+        }
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (ip, stage, fileName, fileSize, chunkCount, codec) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT(ip) DO UPDATE SET stage = excluded.stage, fileName = excluded.fileName,
+                 fileSize = excluded.fileSize, chunkCount = excluded.chunkCount, codec = excluded.codec",
+                connections_table(network_id)
+            ),
+            params![ip, STAGE_UPLOADING, file_name, size as i64, chunk_count as i64, codec],
+        )?;
+        Ok(session)
+    }
+
+    fn staging_path(&self) -> PathBuf {
+        self.staging_root.join(&self.network_id).join(&self.ip).join(&self.file_name)
+    }
+
+    pub fn received_offsets(&self, conn: &Connection) -> rusqlite::Result<Vec<u64>> {
+        let mut stmt = conn.prepare(&format!("SELECT chunkOffset FROM {} WHERE ip = ?1 ORDER BY chunkOffset", chunks_table(&self.network_id)))?;
+        let offsets = stmt
+            .query_map(params![self.ip], |row| row.get::<_, i64>(0))?
+            .map(|offset| offset.map(|offset| offset as u64))
+            .collect::<rusqlite::Result<Vec<u64>>>()?;
+        Ok(offsets)
+    }
+
+    pub fn write_chunk(&self, conn: &Connection, offset: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let end = offset.checked_add(data.len() as u64).filter(|&end| end <= self.size);
+        if end.is_none() {
+            return Err(format!("Chunk of {} bytes at offset {} goes past the end of '{}' ({} bytes)", data.len(), offset, self.file_name, self.size).into());
+        }
+
+        let path = self.staging_path();
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(&path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        file.sync_data()?;
+
+        conn.execute(
+            &format!("INSERT OR REPLACE INTO {} (ip, chunkOffset, chunkSize) VALUES (?1, ?2, ?3)", chunks_table(&self.network_id)),
+            params![self.ip, offset as i64, data.len() as i64],
+        )?;
+        Ok(())
+    }
+
+    // Check that the announced chunks all arrived and cover the file, then move the staged
+    // copy to destination
+    pub fn assemble(&self, conn: &Connection, destination: &Path) -> Result<(), Box<dyn Error>> {
+        let mut stmt = conn.prepare(&format!("SELECT chunkOffset, chunkSize FROM {} WHERE ip = ?1 ORDER BY chunkOffset", chunks_table(&self.network_id)))?;
+        let chunks = stmt
+            .query_map(params![self.ip], |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)))?
+            .collect::<rusqlite::Result<Vec<(u64, u64)>>>()?;
+
+        if chunks.len() != self.chunk_count as usize {
+            return Err(format!("Received {} of the {} chunks of '{}'", chunks.len(), self.chunk_count, self.file_name).into());
+        }
+        let mut expected_offset = 0;
+        for (offset, size) in chunks {
+            if offset < expected_offset {
+                return Err(format!("Chunks of '{}' overlap at bytes {} to {}", self.file_name, offset, expected_offset).into());
+            }
+            if offset > expected_offset {
+                return Err(format!("'{}' is missing bytes {} to {}", self.file_name, expected_offset, offset).into());
+            }
+            expected_offset += size;
+        }
+        if expected_offset != self.size {
+            return Err(format!("Chunks of '{}' add up to {} bytes instead of {}", self.file_name, expected_offset, self.size).into());
+        }
+
+        // No chunk of an empty file is ever written, so there is nothing staged
+        if self.size == 0 {
+            fs::write(destination, [])?;
+            return Ok(());
+        }
+        let path = self.staging_path();
+        let staged_size = fs::metadata(&path)?.len();
+        if staged_size != self.size {
+            return Err(format!("Staged copy of '{}' is {} bytes instead of {}", self.file_name, staged_size, self.size).into());
+        }
+        fs::rename(&path, destination)?;
+        Ok(())
+    }
+
+    // Drop the staged data and chunk list, and put the peer back to idle
+    pub fn discard(&self, conn: &Connection) -> Result<(), Box<dyn Error>> {
+        conn.execute(&format!("DELETE FROM {} WHERE ip = ?1", chunks_table(&self.network_id)), params![self.ip])?;
+        conn.execute(
+            &format!("UPDATE {} SET stage = ?1, fileName = NULL, fileSize = NULL, chunkCount = NULL, codec = NULL WHERE ip = ?2", connections_table(&self.network_id)),
+            params![STAGE_IDLE, self.ip],
+        )?;
+        let path = self.staging_path();
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every test stages under its own temporary directory and removes it when done
+    struct Staging {
+        root: PathBuf,
+        network_id: String,
+    }
+
+    impl Staging {
+        fn new(name: &str) -> (Staging, Connection) {
+            let root = std::env::temp_dir().join(format!("dstorage-uploads-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&root);
+            let network_id = "test".to_string();
+            let conn = Connection::open_in_memory().unwrap();
+            create_tables(&conn, &network_id).unwrap();
+            (Staging { root, network_id }, conn)
+        }
+
+        fn start(&self, conn: &Connection, size: u64, chunk_count: u32) -> UploadSession {
+            UploadSession::start(conn, &self.root, &self.network_id, "10.0.0.7", "notes.txt", size, chunk_count, CodecId::Lz77).unwrap()
+        }
+    }
+
+    impl Drop for Staging {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn resumes_with_the_chunks_already_received() {
+        let (staging, conn) = Staging::new("resume");
+        let session = staging.start(&conn, 10, 2);
+        session.write_chunk(&conn, 0, b"hello").unwrap();
+
+        let resumed = staging.start(&conn, 10, 2);
+        assert_eq!(resumed, session);
+        assert_eq!(resumed.received_offsets(&conn).unwrap(), vec![0]);
+        assert_eq!(UploadSession::load(&conn, &staging.root, &staging.network_id, "10.0.0.7").unwrap(), Some(session));
+
+        // A different upload from the same peer starts over
+        let restarted = staging.start(&conn, 12, 2);
+        assert!(restarted.received_offsets(&conn).unwrap().is_empty());
+        assert!(!restarted.staging_path().exists());
+
+        restarted.discard(&conn).unwrap();
+        assert_eq!(UploadSession::load(&conn, &staging.root, &staging.network_id, "10.0.0.7").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_uploads_it_cant_take() {
+        let (staging, conn) = Staging::new("limits");
+        let start = |file_name: &str, size: u64, chunk_count: u32| {
+            UploadSession::start(&conn, &staging.root, &staging.network_id, "10.0.0.7", file_name, size, chunk_count, CodecId::Stored)
+        };
+        assert!(start("../escape", 10, 1).is_err());
+        assert!(start("notes.txt", MAX_UPLOAD_SIZE + 1, 1).is_err());
+        assert!(start("notes.txt", 10, 0).is_err());
+        assert!(start("notes.txt", 0, 1).is_err());
+        assert!(start("notes.txt", 3, 4).is_err());
+        assert!(start("notes.txt", 0, 0).is_ok());
+        assert!(start("notes.txt", MAX_UPLOAD_SIZE, 1).is_ok());
+    }
+
+    #[test]
+    fn rejects_chunks_outside_the_file() {
+        let (staging, conn) = Staging::new("range");
+        let session = staging.start(&conn, 10, 2);
+        assert!(session.write_chunk(&conn, 8, b"abc").is_err());
+        assert!(session.write_chunk(&conn, u64::MAX, b"a").is_err());
+        assert!(session.received_offsets(&conn).unwrap().is_empty());
+        session.write_chunk(&conn, 7, b"abc").unwrap();
+        assert_eq!(session.received_offsets(&conn).unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn assembles_only_chunks_that_cover_the_file() {
+        let (staging, conn) = Staging::new("assemble");
+        let destination = staging.root.join("notes.txt");
+
+        let session = staging.start(&conn, 10, 2);
+        session.write_chunk(&conn, 0, b"0123").unwrap();
+        let error = session.assemble(&conn, &destination).unwrap_err().to_string();
+        assert!(error.contains("Received 1 of the 2 chunks"), "{}", error);
+
+        session.write_chunk(&conn, 6, b"6789").unwrap();
+        let error = session.assemble(&conn, &destination).unwrap_err().to_string();
+        assert!(error.contains("missing bytes 4 to 6"), "{}", error);
+
+        let session = staging.start(&conn, 10, 2);
+        session.discard(&conn).unwrap();
+        let session = staging.start(&conn, 10, 2);
+        session.write_chunk(&conn, 0, b"012345").unwrap();
+        session.write_chunk(&conn, 4, b"456789").unwrap();
+        let error = session.assemble(&conn, &destination).unwrap_err().to_string();
+        assert!(error.contains("overlap at bytes 4 to 6"), "{}", error);
+
+        session.discard(&conn).unwrap();
+        let session = staging.start(&conn, 10, 2);
+        session.write_chunk(&conn, 5, b"56789").unwrap();
+        session.write_chunk(&conn, 0, b"01234").unwrap();
+        session.assemble(&conn, &destination).unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), b"0123456789");
+        assert!(!session.staging_path().exists());
+    }
+
+    #[test]
+    fn offsets_round_trip() {
+        let offsets = vec![0, 4096, u64::MAX];
+        assert_eq!(decode_offsets(&encode_offsets(&offsets)).unwrap(), offsets);
+        assert!(decode_offsets(&[0; 7]).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+// Big-endian integers and strings prefixed with their u16 length, which messages and
+// manifests are both laid out in. Errors name what was being read or written, a message
+// or a manifest.
+
+pub fn write_string(bytes: &mut Vec<u8>, value: &str, what: &str) -> Result<(), String> {
+    let length = u16::try_from(value.len()).map_err(|_| format!("'{}' is too long for a {}", value, what.to_lowercase()))?;
+    bytes.extend_from_slice(&length.to_be_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+pub struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    what: &'static str,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8], what: &'static str) -> ByteReader<'a> {
+        ByteReader { bytes, position: 0, what }
+    }
+
+    pub fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| format!("{} is cut short", self.what))?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let length = self.u16()? as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| format!("{} string is not UTF-8", self.what))
+    }
+
+    // Everything not read yet
+    pub fn rest(&mut self) -> Vec<u8> {
+        let rest = self.bytes[self.position..].to_vec();
+        self.position = self.bytes.len();
+        rest
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+}